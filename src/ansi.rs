@@ -0,0 +1,242 @@
+use std::borrow::Cow;
+
+/// How text is drawn as set by SGR sequences. Colors are indices of the 256
+/// color palette and `None` is the terminal's default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Style {
+    pub bg: Option<u8>,
+    pub blinks: bool,
+    pub fg: Option<u8>,
+    pub is_bold: bool,
+    pub is_dim: bool,
+    pub is_italic: bool,
+    pub is_reversed: bool,
+    pub is_underlined: bool,
+}
+
+/// Styles of a text, each with the byte offset it starts at, sorted by the
+/// offsets. The text before the first one has the default style.
+pub type Styles = Vec<(usize, Style)>;
+
+/// Returns `s` without escape sequences, along with the styles its SGR
+/// sequences set.
+pub fn parse(s: &str) -> (Cow<'_, str>, Styles) {
+    if !s.contains('\x1b') {
+        return (Cow::Borrowed(s), Vec::new());
+    }
+    let mut text = String::with_capacity(s.len());
+    let mut styles: Styles = Vec::new();
+    let mut style = Style::default();
+    let mut rest = s;
+    while let Some(i) = rest.find('\x1b') {
+        text.push_str(&rest[..i]);
+        let (len, params) = sequence_at(&rest[i..]);
+        if let Some(params) = params {
+            style.apply(params);
+            // A style replaces the previous one if no text is in between.
+            if styles.last().map(|&(offset, _)| offset) == Some(text.len()) {
+                styles.pop();
+            }
+            let last_style = styles.last().map(|&(_, style)| style).unwrap_or_default();
+            if style != last_style {
+                styles.push((text.len(), style));
+            }
+        }
+        rest = &rest[i + len..];
+    }
+    text.push_str(rest);
+    (Cow::Owned(text), styles)
+}
+
+// Returns the length of the escape sequence `s` starts with, along with its
+// parameters if it is an SGR sequence.
+fn sequence_at(s: &str) -> (usize, Option<&str>) {
+    let bytes = s.as_bytes();
+    match bytes.get(1) {
+        // CSI: parameters and intermediate bytes, then a final byte.
+        Some(b'[') => match bytes[2..].iter().position(|b| (0x40..=0x7e).contains(b)) {
+            Some(i) if bytes[2 + i] == b'm' => (3 + i, Some(&s[2..2 + i])),
+            Some(i) => (3 + i, None),
+            None => (s.len(), None),
+        },
+        // OSC, e.g. a hyperlink, terminated by BEL or ST.
+        Some(b']') => {
+            let mut i = 2;
+            while i < bytes.len() {
+                match bytes[i] {
+                    0x07 => return (i + 1, None),
+                    0x1b if bytes.get(i + 1) == Some(&b'\\') => return (i + 2, None),
+                    _ => i += 1,
+                }
+            }
+            (s.len(), None)
+        }
+        // Intermediate bytes, then a final byte, e.g. `ESC ( B`.
+        Some(0x20..=0x2f) => match bytes[2..].iter().position(|b| (0x30..=0x7e).contains(b)) {
+            Some(i) => (3 + i, None),
+            None => (s.len(), None),
+        },
+        Some(b) if b.is_ascii() => (2, None),
+        _ => (1, None),
+    }
+}
+
+impl Style {
+    // Applies the parameters of an SGR sequence, such as `1;38;5;208`.
+    fn apply(&mut self, params: &str) {
+        let mut params = params
+            .split([';', ':'])
+            .map(|param| param.parse::<u32>().unwrap_or(0));
+        while let Some(param) = params.next() {
+            match param {
+                0 => *self = Style::default(),
+                1 => self.is_bold = true,
+                2 => self.is_dim = true,
+                3 => self.is_italic = true,
+                4 => self.is_underlined = true,
+                5 | 6 => self.blinks = true,
+                7 => self.is_reversed = true,
+                22 => {
+                    self.is_bold = false;
+                    self.is_dim = false;
+                }
+                23 => self.is_italic = false,
+                24 => self.is_underlined = false,
+                25 => self.blinks = false,
+                27 => self.is_reversed = false,
+                30..=37 => self.fg = Some((param - 30) as u8),
+                38 => self.fg = extended_color(&mut params),
+                39 => self.fg = None,
+                40..=47 => self.bg = Some((param - 40) as u8),
+                48 => self.bg = extended_color(&mut params),
+                49 => self.bg = None,
+                90..=97 => self.fg = Some((param - 90 + 8) as u8),
+                100..=107 => self.bg = Some((param - 100 + 8) as u8),
+                _ => {}
+            }
+        }
+    }
+}
+
+// Reads the rest of `38;5;n` or `38;2;r;g;b`, approximating an RGB color with
+// the nearest one of the 6x6x6 color cube.
+fn extended_color<I: Iterator<Item = u32>>(params: &mut I) -> Option<u8> {
+    match params.next() {
+        Some(5) => params.next().map(|n| n.min(255) as u8),
+        Some(2) => {
+            let mut level = || (params.next().unwrap_or(0).min(255) * 5 + 127) / 255;
+            let (r, g, b) = (level(), level(), level());
+            Some((16 + 36 * r + 6 * g + b) as u8)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bold() -> Style {
+        Style {
+            is_bold: true,
+            ..Style::default()
+        }
+    }
+
+    fn fg(color: u8) -> Style {
+        Style {
+            fg: Some(color),
+            ..Style::default()
+        }
+    }
+
+    #[test]
+    fn text_without_escapes_is_borrowed() {
+        let (text, styles) = parse("plain");
+        assert!(matches!(text, Cow::Borrowed("plain")));
+        assert!(styles.is_empty());
+    }
+
+    #[test]
+    fn sgr_sets_styles_at_byte_offsets() {
+        let (text, styles) = parse("a\x1b[1mbé\x1b[0mc");
+        assert_eq!(text, "abéc");
+        assert_eq!(styles, vec![(1, bold()), (4, Style::default())]);
+    }
+
+    #[test]
+    fn empty_sgr_resets() {
+        let (_, styles) = parse("\x1b[31mx\x1b[my");
+        assert_eq!(styles, vec![(0, fg(1)), (1, Style::default())]);
+    }
+
+    #[test]
+    fn attributes_are_reset_one_by_one() {
+        let (_, styles) = parse("\x1b[1;4;31mx\x1b[22;39my");
+        let underlined = Style {
+            is_underlined: true,
+            ..Style::default()
+        };
+        assert_eq!(styles[1], (1, underlined));
+    }
+
+    #[test]
+    fn sgr_at_the_same_offset_replaces_the_earlier_one() {
+        let (text, styles) = parse("\x1b[31m\x1b[1mx");
+        assert_eq!(text, "x");
+        let bold_red = Style {
+            fg: Some(1),
+            is_bold: true,
+            ..Style::default()
+        };
+        assert_eq!(styles, vec![(0, bold_red)]);
+        // Back to the style before it, so no style is left at the offset.
+        let (_, styles) = parse("\x1b[1mx\x1b[0m\x1b[1my");
+        assert_eq!(styles, vec![(0, bold())]);
+        let (_, styles) = parse("\x1b[1m\x1b[0mx");
+        assert!(styles.is_empty());
+    }
+
+    #[test]
+    fn extended_colors_are_palette_indices() {
+        assert_eq!(parse("\x1b[38;5;208mx").1, vec![(0, fg(208))]);
+        assert_eq!(parse("\x1b[38:5:300mx").1, vec![(0, fg(255))]);
+        assert_eq!(parse("\x1b[38;2;255;0;0mx").1, vec![(0, fg(196))]);
+        assert_eq!(
+            parse("\x1b[38;2;0;128;255mx").1,
+            vec![(0, fg(16 + 6 * 3 + 5))]
+        );
+        let (_, styles) = parse("\x1b[48;5;21;1mx");
+        let style = Style {
+            bg: Some(21),
+            is_bold: true,
+            ..Style::default()
+        };
+        assert_eq!(styles, vec![(0, style)]);
+        assert_eq!(parse("\x1b[96mx").1, vec![(0, fg(14))]);
+    }
+
+    #[test]
+    fn other_sequences_are_removed() {
+        assert_eq!(parse("a\x1b[2Kb\x1b(Bc").0, "abc");
+        // CSI without a final byte runs to the end.
+        assert_eq!(parse("a\x1b[12;3").0, "a");
+        assert_eq!(sequence_at("\x1b[12;3"), (6, None));
+        assert_eq!(parse("a\x1b").0, "a");
+    }
+
+    #[test]
+    fn osc_ends_with_bel_or_st() {
+        let link = "\x1b]8;;https://example.com\x07link\x1b]8;;\x1b\\!";
+        assert_eq!(parse(link).0, "link!");
+        assert_eq!(sequence_at("\x1b]0;title\x07x"), (10, None));
+        assert_eq!(sequence_at("\x1b]0;title\x1b\\x"), (11, None));
+        assert_eq!(sequence_at("\x1b]0;title"), (9, None));
+    }
+
+    #[test]
+    fn sgr_parameters_are_returned() {
+        assert_eq!(sequence_at("\x1b[1;31mx"), (7, Some("1;31")));
+        assert_eq!(sequence_at("\x1b[mx"), (3, Some("")));
+    }
+}
@@ -4,10 +4,10 @@ use std::sync::{Arc, RwLock};
 use crate::commander::Commander;
 use crate::config::Config;
 use crate::coordinator::Coordinator;
-use crate::line::Line;
 use crate::line_storage::LineStorage;
 use crate::reader::Reader;
 use crate::screen::{self, Screen};
+use crate::search::Generation;
 use crate::searcher::Searcher;
 use crate::state::State;
 use crate::thread_util::spawn_with_name;
@@ -32,29 +32,38 @@ impl App {
         let (searcher_reply_tx, searcher_reply_rx) = channel();
         let (state_input_tx, state_input_rx) = channel();
         let (state_reply_tx, state_reply_rx) = channel();
-        let line_storage = Arc::new(RwLock::new(LineStorage::new()));
+        let line_storage = Arc::new(RwLock::new(LineStorage::new(&self.config)));
+        let generation = Generation::default();
 
         spawn_with_name("commander", move || {
             let commander = Commander::new();
             commander.start(commander_tx);
         });
 
-        let config = self.config.clone();
-        let line_storage_ = line_storage.clone();
+        // Made here so that the state can rerun the source command.
+        let reader = Reader::new(self.config.clone(), line_storage.clone());
+        let source_command = reader.source_command();
         spawn_with_name("reader", move || {
-            let reader = Reader::new(config, line_storage_);
             reader.start(reader_tx);
         });
 
+        let config = self.config.clone();
+        let generation_ = generation.clone();
         let line_storage_ = line_storage.clone();
         spawn_with_name("searcher", move || {
-            let searcher = Searcher::new(line_storage_);
+            let searcher = Searcher::new(&config, generation_, line_storage_);
             searcher.start(searcher_input_rx, searcher_reply_tx);
         });
 
         let config = self.config;
         spawn_with_name("state", move || {
-            let state = State::new(&config, line_storage, Screen::new());
+            let state = State::new(
+                &config,
+                generation,
+                line_storage,
+                source_command,
+                Screen::new(&config),
+            );
             state.start(state_input_rx, state_reply_tx);
         });
 
@@ -71,14 +80,14 @@ impl App {
     }
 }
 
-fn print_result(result: Option<Vec<Arc<Line>>>) {
+// Prints the selected lines, each of which ends with its delimiter.
+fn print_result(result: Option<Vec<Vec<u8>>>) {
     use std::io::{self, Write};
     if let Some(lines) = result {
         let stdout_ = io::stdout();
         let mut stdout = stdout_.lock();
         for line in lines {
-            stdout.write_all(line.as_bytes()).unwrap();
-            stdout.write_all(b"\n").unwrap();
+            stdout.write_all(&line).unwrap();
         }
         stdout.flush().unwrap();
     }
@@ -1,5 +1,6 @@
 use std::fs::File;
 use std::io::Read;
+use std::str;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
 use std::sync::Once;
@@ -36,15 +37,35 @@ impl Commander {
             }
             thread::sleep(Duration::from_millis(50));
         });
-        let tty = File::open("/dev/tty").unwrap();
-        for byte in tty.bytes() {
-            let _dont_care = tx
-                .send(KeyDown(Key::from_u32(byte.unwrap() as u32)))
-                .is_ok();
+        let mut tty = File::open("/dev/tty").unwrap();
+        let mut buf = [0; 4];
+        while tty.read_exact(&mut buf[..1]).is_ok() {
+            let len = utf8_len(buf[0]);
+            if tty.read_exact(&mut buf[1..len]).is_err() {
+                break;
+            }
+            // A terminal sends no invalid sequences, so any are dropped.
+            if let Some(ch) = str::from_utf8(&buf[..len])
+                .ok()
+                .and_then(|s| s.chars().next())
+            {
+                let _dont_care = tx.send(KeyDown(Key::from_u32(ch as u32))).is_ok();
+            }
         }
     }
 }
 
+// Returns the length of the UTF-8 sequence that starts with `byte`, or 1 if
+// it cannot start one.
+fn utf8_len(byte: u8) -> usize {
+    match byte {
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => 1,
+    }
+}
+
 fn install_sigwinch_handler_once() {
     INSTALL.call_once(|| unsafe {
         let h = sigwinch_handler as *mut libc::c_void as libc::sighandler_t;
@@ -1,57 +1,386 @@
-use clap::{App, Arg, ArgMatches};
+use clap::{App, Arg, ArgMatches, ErrorKind};
 use libc::{c_int, isatty};
-use std::fs::File;
+use std::env;
+use std::ffi::OsString;
 use std::io;
+use std::sync::Arc;
+
+use crate::encoding::InputEncoding;
+use crate::field::{self, Fields};
+use crate::pattern::CaseMatching;
+use crate::romaji::Dictionary;
+use crate::unique::Occurrence;
 
 #[derive(Clone)]
 pub struct Config {
+    ansi: bool,
+    cache_size: usize,
+    case_matching: CaseMatching,
+    delimiter: Option<String>,
+    encoding: InputEncoding,
+    folds_kana: bool,
+    follows: bool,
+    header: Option<String>,
+    header_lines: usize,
     initial_query: Option<String>,
-    input_file_path: Option<String>,
+    input_file_paths: Vec<String>,
     is_cjk: bool,
+    is_regex: bool,
+    nth: Option<String>,
+    prints0: bool,
+    prints_origin: bool,
+    reads0: bool,
+    romaji_dictionary: Option<Arc<Dictionary>>,
+    shows_cache_stats: bool,
+    shows_origin: bool,
+    sorts: bool,
+    source_command: Option<String>,
+    strips_diacritics: bool,
+    unique: Option<Occurrence>,
+    uses_mmap: bool,
+    uses_romaji: bool,
+    with_nth: Option<String>,
 }
 
 impl Config {
     pub fn with_args() -> Self {
-        let m = get_arg_matches();
+        Self::with_arg_matches(get_arg_matches(env::args_os()))
+    }
+
+    /// Parses `args` as if they followed the program name on the command
+    /// line.
+    #[cfg(test)]
+    pub fn with_test_args(args: &[&str]) -> Self {
+        let args = ["eru"].iter().chain(args.iter()).map(OsString::from);
+        Self::with_arg_matches(get_arg_matches(args))
+    }
+
+    fn with_arg_matches(m: ArgMatches<'_>) -> Self {
+        let case_matching = if m.is_present("ignore-case") {
+            CaseMatching::Ignore
+        } else if m.is_present("case-sensitive") {
+            CaseMatching::Respect
+        } else {
+            CaseMatching::Smart
+        };
         Config {
+            ansi: m.is_present("ansi"),
+            cache_size: parse_size(m.value_of("cache-size").unwrap()).unwrap(),
+            case_matching,
+            delimiter: m.value_of("delimiter").map(|s| s.to_owned()),
+            encoding: InputEncoding::from_label(m.value_of("encoding").unwrap()).unwrap(),
+            folds_kana: m.is_present("fold-kana"),
+            follows: m.is_present("follow"),
+            header: m.value_of("header").map(|s| s.to_owned()),
+            header_lines: m.value_of("header-lines").unwrap().parse().unwrap(),
             initial_query: m.value_of("query").map(|s| s.to_owned()),
-            input_file_path: m.value_of("INPUT").map(|s| s.to_owned()),
+            input_file_paths: m
+                .values_of("INPUT")
+                .map(|values| values.map(|s| s.to_owned()).collect())
+                .unwrap_or_default(),
             is_cjk: m.is_present("cjk"),
+            is_regex: m.is_present("regex"),
+            nth: m.value_of("nth").map(|s| s.to_owned()),
+            prints0: m.is_present("print0"),
+            prints_origin: m.is_present("print-origin"),
+            reads0: m.is_present("read0"),
+            romaji_dictionary: m.value_of("romaji-dict").map(|path| {
+                // Read once here rather than validated by clap and read
+                // again, as the file may change in between.
+                let dictionary = Dictionary::read(path).unwrap_or_else(|e| {
+                    clap::Error::with_description(&e, ErrorKind::InvalidValue).exit()
+                });
+                Arc::new(dictionary)
+            }),
+            shows_cache_stats: m.is_present("cache-stats"),
+            shows_origin: m.is_present("show-origin"),
+            sorts: !m.is_present("no-sort"),
+            source_command: m.value_of("source-cmd").map(|s| s.to_owned()),
+            strips_diacritics: m.is_present("normalize"),
+            unique: match m.value_of("unique") {
+                _ if !m.is_present("unique") => None,
+                Some("last") => Some(Occurrence::Last),
+                _ => Some(Occurrence::First),
+            },
+            uses_mmap: !m.is_present("no-mmap"),
+            uses_romaji: m.is_present("romaji") || m.is_present("romaji-dict"),
+            with_nth: m.value_of("with-nth").map(|s| s.to_owned()),
         }
     }
 
+    /// Returns true if ANSI escape sequences in lines are stripped for
+    /// matching and their colors shown.
+    pub fn ansi(&self) -> bool {
+        self.ansi
+    }
+
+    /// Returns the memory budget of the match info cache in bytes.
+    pub fn cache_size(&self) -> usize {
+        self.cache_size
+    }
+
+    pub fn case_matching(&self) -> CaseMatching {
+        self.case_matching
+    }
+
+    /// Returns the fields shown in the list, or `None` to show whole lines.
+    pub fn display_fields(&self) -> Option<Fields> {
+        let with_nth = self.with_nth.as_ref()?;
+        Some(Fields::new(self.delimiter.as_ref().map(|s| s.as_ref()), with_nth).unwrap())
+    }
+
+    pub fn encoding(&self) -> InputEncoding {
+        self.encoding
+    }
+
+    pub fn folds_kana(&self) -> bool {
+        self.folds_kana
+    }
+
+    /// Returns true if input files are watched for appended lines.
+    pub fn follows(&self) -> bool {
+        self.follows
+    }
+
+    /// Returns the text shown above the list, if any.
+    pub fn header(&self) -> Option<&str> {
+        self.header.as_ref().map(|s| s.as_ref())
+    }
+
+    /// Returns the number of first input lines that are shown above the list
+    /// instead of in it.
+    pub fn header_lines(&self) -> usize {
+        self.header_lines
+    }
+
     pub fn initial_query(&self) -> Option<&str> {
         self.initial_query.as_ref().map(|s| s.as_ref())
     }
 
+    /// Returns the byte input lines end with, which is NUL with `--read0`.
+    pub fn input_delimiter(&self) -> u8 {
+        if self.reads0 {
+            0
+        } else {
+            b'\n'
+        }
+    }
+
+    pub fn input_file_paths(&self) -> &[String] {
+        &self.input_file_paths
+    }
+
+    /// Returns the standard input to read if no files or command are given.
     pub fn input_source(&self) -> Box<dyn io::Read> {
-        if let Some(ref path) = self.input_file_path {
-            if let Ok(f) = File::open(path) {
-                return Box::new(f);
-            }
-        } else if !stdin_is_tty() {
+        if self.input_file_paths.is_empty() && self.source_command.is_none() && !stdin_is_tty() {
             return Box::new(io::stdin());
         }
         Box::new(io::empty())
     }
 
+    /// Returns the names of the inputs indexed by the origin of lines.
+    pub fn origin_names(&self) -> Vec<String> {
+        if let Some(ref command) = self.source_command {
+            vec![command.clone()]
+        } else if self.input_file_paths.is_empty() {
+            vec!["(standard input)".to_owned()]
+        } else {
+            self.input_file_paths.clone()
+        }
+    }
+
     pub fn is_cjk(&self) -> bool {
         self.is_cjk
     }
+
+    pub fn is_regex(&self) -> bool {
+        self.is_regex
+    }
+
+    /// Returns the byte printed after each selected line, which is NUL with
+    /// `--print0`.
+    pub fn output_delimiter(&self) -> u8 {
+        if self.prints0 {
+            0
+        } else {
+            b'\n'
+        }
+    }
+
+    /// Returns true if selected lines are printed after the name of the
+    /// file they came from, as in `path:line`.
+    pub fn prints_origin(&self) -> bool {
+        self.prints_origin
+    }
+
+    /// Returns the dictionary given by `--romaji-dict`, if any.
+    pub fn romaji_dictionary(&self) -> Option<Arc<Dictionary>> {
+        self.romaji_dictionary.clone()
+    }
+
+    /// Returns the fields queries are matched against, or `None` to match
+    /// whole lines.
+    pub fn search_fields(&self) -> Option<Fields> {
+        let nth = self.nth.as_ref()?;
+        Some(Fields::new(self.delimiter.as_ref().map(|s| s.as_ref()), nth).unwrap())
+    }
+
+    pub fn shows_cache_stats(&self) -> bool {
+        self.shows_cache_stats
+    }
+
+    /// Returns true if lines are listed after the name of the file they came
+    /// from.
+    pub fn shows_origin(&self) -> bool {
+        self.shows_origin
+    }
+
+    pub fn sorts(&self) -> bool {
+        self.sorts
+    }
+
+    /// Returns the shell command whose output is read instead of the input
+    /// files or standard input, if any.
+    pub fn source_command(&self) -> Option<&str> {
+        self.source_command.as_ref().map(|s| s.as_ref())
+    }
+
+    pub fn strips_diacritics(&self) -> bool {
+        self.strips_diacritics
+    }
+
+    /// Returns which of identical input lines are kept if only one of them
+    /// is.
+    pub fn unique(&self) -> Option<Occurrence> {
+        self.unique
+    }
+
+    /// Returns true if input files may be mapped into memory rather than
+    /// read.
+    pub fn uses_mmap(&self) -> bool {
+        self.uses_mmap
+    }
+
+    pub fn uses_romaji(&self) -> bool {
+        self.uses_romaji
+    }
 }
 
-fn get_arg_matches<'a>() -> ArgMatches<'a> {
+fn get_arg_matches<'a, I: IntoIterator<Item = OsString>>(args: I) -> ArgMatches<'a> {
     App::new("eru")
         .version(env!("CARGO_PKG_VERSION"))
-        .arg(Arg::with_name("INPUT").index(1))
+        .arg(Arg::with_name("INPUT").index(1).multiple(true))
         .arg(
             Arg::with_name("query")
                 .long("query")
                 .short("q")
                 .takes_value(true),
         )
+        .arg(Arg::with_name("ansi").long("ansi"))
+        .arg(
+            Arg::with_name("cache-size")
+                .long("cache-size")
+                .takes_value(true)
+                .default_value("256M")
+                .validator(|s| parse_size(&s).map(|_| ())),
+        )
+        .arg(Arg::with_name("cache-stats").long("cache-stats"))
         .arg(Arg::with_name("cjk").long("cjk"))
-        .get_matches()
+        .arg(
+            Arg::with_name("delimiter")
+                .long("delimiter")
+                .short("d")
+                .takes_value(true)
+                .validator(field::validate_delimiter),
+        )
+        .arg(
+            Arg::with_name("encoding")
+                .long("encoding")
+                .takes_value(true)
+                .default_value("utf-8")
+                .validator(|s| match InputEncoding::from_label(&s) {
+                    Some(_) => Ok(()),
+                    None => Err(format!("unknown encoding: {}", s)),
+                }),
+        )
+        .arg(Arg::with_name("fold-kana").long("fold-kana"))
+        .arg(Arg::with_name("follow").long("follow").requires("INPUT"))
+        .arg(Arg::with_name("header").long("header").takes_value(true))
+        .arg(
+            Arg::with_name("header-lines")
+                .long("header-lines")
+                .takes_value(true)
+                .default_value("0")
+                .validator(|s| s.parse::<usize>().map(|_| ()).map_err(|e| e.to_string())),
+        )
+        .arg(
+            Arg::with_name("ignore-case")
+                .long("ignore-case")
+                .short("i")
+                .conflicts_with("case-sensitive"),
+        )
+        .arg(
+            Arg::with_name("case-sensitive")
+                .long("case-sensitive")
+                .short("s"),
+        )
+        .arg(Arg::with_name("no-mmap").long("no-mmap"))
+        .arg(Arg::with_name("no-sort").long("no-sort"))
+        .arg(
+            Arg::with_name("nth")
+                .long("nth")
+                .short("n")
+                .takes_value(true)
+                .validator(field::validate_ranges),
+        )
+        .arg(
+            Arg::with_name("with-nth")
+                .long("with-nth")
+                .takes_value(true)
+                .validator(field::validate_ranges),
+        )
+        .arg(Arg::with_name("normalize").long("normalize"))
+        .arg(Arg::with_name("print-origin").long("print-origin"))
+        .arg(Arg::with_name("print0").long("print0"))
+        .arg(Arg::with_name("read0").long("read0"))
+        .arg(Arg::with_name("regex").long("regex"))
+        .arg(Arg::with_name("romaji").long("romaji"))
+        .arg(Arg::with_name("show-origin").long("show-origin"))
+        .arg(
+            Arg::with_name("romaji-dict")
+                .long("romaji-dict")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("unique")
+                .long("unique")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .possible_values(&["first", "last"]),
+        )
+        .arg(
+            Arg::with_name("source-cmd")
+                .long("source-cmd")
+                .takes_value(true)
+                .conflicts_with("INPUT"),
+        )
+        .get_matches_from(args)
+}
+
+// Parses a size in bytes with an optional `K`, `M` or `G` suffix.
+fn parse_size(s: &str) -> Result<usize, String> {
+    let (digits, unit) = match s.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M')) | Some((i, 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G')) | Some((i, 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| format!("invalid size: {}", s))
 }
 
 fn stdin_is_tty() -> bool {
@@ -1,8 +1,6 @@
 use std::sync::mpsc::{Receiver, Sender};
-use std::sync::Arc;
 
 use crate::commander;
-use crate::line::Line;
 use crate::reader;
 use crate::searcher;
 use crate::state;
@@ -23,7 +21,7 @@ impl Coordinator {
         searcher_reply_rx: Receiver<searcher::Reply>,
         state_input_tx: Sender<state::Input>,
         state_reply_rx: Receiver<state::Reply>,
-    ) -> Option<Vec<Arc<Line>>> {
+    ) -> Option<Vec<Vec<u8>>> {
         let state_input_tx_ = state_input_tx.clone();
         spawn_with_name("coordinator::commander_event", move || {
             while let Ok(event) = commander_rx.recv() {
@@ -93,10 +91,7 @@ fn process_searcher_reply(reply: searcher::Reply, tx: &Sender<state::Input>) {
     }
 }
 
-fn process_state_reply(
-    reply: state::Reply,
-    tx: &Sender<searcher::Input>,
-) -> Option<Vec<Arc<Line>>> {
+fn process_state_reply(reply: state::Reply, tx: &Sender<searcher::Input>) -> Option<Vec<Vec<u8>>> {
     use searcher::Input::*;
     use state::Reply::*;
     match reply {
@@ -0,0 +1,181 @@
+use encoding_rs::{
+    Encoding, EUC_JP, ISO_2022_JP, SHIFT_JIS, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252,
+};
+use memchr::memrchr;
+use std::str;
+
+/// The encoding of input lines given by `--encoding`.
+#[derive(Clone, Copy)]
+pub enum InputEncoding {
+    Auto,
+    Fixed(&'static Encoding),
+}
+
+impl InputEncoding {
+    /// Returns the encoding named `label`, which is `auto` or a label such as
+    /// `shift_jis`, `euc-jp`, `iso-2022-jp`, `utf-16le` or `latin1`.
+    pub fn from_label(label: &str) -> Option<Self> {
+        if label.eq_ignore_ascii_case("auto") {
+            return Some(InputEncoding::Auto);
+        }
+        Encoding::for_label(label.as_bytes()).map(InputEncoding::Fixed)
+    }
+
+    /// Returns the encoding of an input that starts with `sample`.
+    pub fn resolve(self, sample: &[u8]) -> &'static Encoding {
+        match self {
+            InputEncoding::Auto => detect(sample),
+            InputEncoding::Fixed(encoding) => encoding,
+        }
+    }
+}
+
+/// Returns true if lines in `encoding` cannot be split on ASCII bytes and
+/// must be decoded as they are read.
+pub fn is_utf16(encoding: &'static Encoding) -> bool {
+    encoding == UTF_16LE || encoding == UTF_16BE
+}
+
+/// Encodes `s` in `encoding`, which must be UTF-16. `Encoding::encode`
+/// cannot do it as it only outputs ASCII-compatible encodings.
+pub fn encode_utf16(s: &str, encoding: &'static Encoding) -> Vec<u8> {
+    debug_assert!(is_utf16(encoding));
+    s.encode_utf16()
+        .flat_map(|unit| {
+            if encoding == UTF_16BE {
+                unit.to_be_bytes()
+            } else {
+                unit.to_le_bytes()
+            }
+        })
+        .collect()
+}
+
+// Guesses the encoding of `sample` among UTF-8, UTF-16, the Japanese ones
+// and Latin-1, which decodes anything.
+fn detect(sample: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(sample) {
+        return encoding;
+    }
+    // Without a BOM, UTF-16 of mostly ASCII text has every other byte zero.
+    let zeros_at = |parity| {
+        sample
+            .iter()
+            .skip(parity)
+            .step_by(2)
+            .filter(|&&b| b == 0)
+            .count()
+    };
+    let (even_zeros, odd_zeros) = (zeros_at(0), zeros_at(1));
+    if odd_zeros > sample.len() / 4 && even_zeros == 0 {
+        return UTF_16LE;
+    }
+    if even_zeros > sample.len() / 4 && odd_zeros == 0 {
+        return UTF_16BE;
+    }
+    // The sample may end in the middle of a char.
+    let sample = match memrchr(b'\n', sample) {
+        Some(i) => &sample[..=i],
+        None => sample,
+    };
+    // ISO-2022-JP is 7-bit, so it is valid UTF-8 as well.
+    if [&b"\x1b$B"[..], b"\x1b$@", b"\x1b(J", b"\x1b(I"]
+        .iter()
+        .any(|escape| sample.windows(escape.len()).any(|w| w == *escape))
+    {
+        return ISO_2022_JP;
+    }
+    match str::from_utf8(sample) {
+        Ok(_) => return UTF_8,
+        // Valid but for a char cut off at the end of the sample.
+        Err(e) if e.error_len().is_none() => return UTF_8,
+        Err(_) => {}
+    }
+    // Shift_JIS uses lead bytes EUC-JP does not, while EUC-JP text often
+    // happens to be valid Shift_JIS, so EUC-JP is tried first.
+    for &encoding in [EUC_JP, SHIFT_JIS].iter() {
+        if encoding
+            .decode_without_bom_handling_and_without_replacement(sample)
+            .is_some()
+        {
+            return encoding;
+        }
+    }
+    WINDOWS_1252
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(s: &str, encoding: &'static Encoding) -> Vec<u8> {
+        encoding.encode(s).0.into_owned()
+    }
+
+    const TEXT: &str = "日本語のテキスト、かなとカナ。\n二行目です。\n";
+
+    #[test]
+    fn bom_decides_encoding() {
+        let mut utf8 = b"\xEF\xBB\xBF".to_vec();
+        utf8.extend_from_slice(TEXT.as_bytes());
+        assert_eq!(detect(&utf8), UTF_8);
+        let mut utf16le = b"\xFF\xFE".to_vec();
+        utf16le.extend(encode_utf16(TEXT, UTF_16LE));
+        assert_eq!(detect(&utf16le), UTF_16LE);
+        let mut utf16be = b"\xFE\xFF".to_vec();
+        utf16be.extend(encode_utf16(TEXT, UTF_16BE));
+        assert_eq!(detect(&utf16be), UTF_16BE);
+    }
+
+    #[test]
+    fn utf16_of_ascii_is_detected_without_bom() {
+        assert_eq!(detect(&encode_utf16("foo\nbar\n", UTF_16LE)), UTF_16LE);
+        assert_eq!(detect(&encode_utf16("foo\nbar\n", UTF_16BE)), UTF_16BE);
+    }
+
+    #[test]
+    fn japanese_encodings_are_told_apart() {
+        assert_eq!(detect(&encode(TEXT, ISO_2022_JP)), ISO_2022_JP);
+        assert_eq!(detect(&encode(TEXT, EUC_JP)), EUC_JP);
+        assert_eq!(detect(&encode(TEXT, SHIFT_JIS)), SHIFT_JIS);
+    }
+
+    #[test]
+    fn utf8_cut_in_the_middle_of_a_char_is_utf8() {
+        let bytes = TEXT.as_bytes();
+        assert_eq!(detect(bytes), UTF_8);
+        // No newline to trim the sample to, and one byte short of `日`.
+        let line = "テキスト".repeat(10);
+        assert_eq!(detect(&line.as_bytes()[..line.len() - 1]), UTF_8);
+        let mut cut = bytes.to_vec();
+        cut.pop();
+        assert_eq!(detect(&cut), UTF_8);
+    }
+
+    #[test]
+    fn invalid_utf8_in_the_middle_is_not_utf8() {
+        assert_eq!(detect(b"caf\xe9 cr\xe8me\n"), WINDOWS_1252);
+    }
+
+    #[test]
+    fn labels_name_encodings() {
+        assert!(matches!(
+            InputEncoding::from_label("AUTO"),
+            Some(InputEncoding::Auto)
+        ));
+        assert!(
+            matches!(InputEncoding::from_label("sjis"), Some(InputEncoding::Fixed(e)) if e == SHIFT_JIS)
+        );
+        assert!(
+            matches!(InputEncoding::from_label("latin1"), Some(InputEncoding::Fixed(e)) if e == WINDOWS_1252)
+        );
+        assert!(InputEncoding::from_label("klingon").is_none());
+    }
+
+    #[test]
+    fn encode_utf16_follows_byte_order() {
+        assert_eq!(encode_utf16("a\n", UTF_16LE), b"a\0\n\0");
+        assert_eq!(encode_utf16("a\n", UTF_16BE), b"\0a\0\n");
+        assert_eq!(encode_utf16("😀", UTF_16LE), b"\x3d\xd8\x00\xde");
+    }
+}
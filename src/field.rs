@@ -0,0 +1,194 @@
+use regex::Regex;
+use std::ops::Range;
+
+/// Splits lines into fields and picks some of them, as in `--nth 2,4..` or
+/// `--with-nth 2..`.
+#[derive(Clone)]
+pub struct Fields {
+    delimiter: Option<Regex>,
+    ranges: Vec<FieldRange>,
+}
+
+// 1-based and inclusive; negative indices count from the last field.
+#[derive(Clone, Copy)]
+struct FieldRange {
+    start: Option<isize>,
+    end: Option<isize>,
+}
+
+impl Fields {
+    /// `delimiter` is a regex. Without it, fields are separated by runs of
+    /// whitespace like AWK.
+    pub fn new(delimiter: Option<&str>, ranges: &str) -> Result<Self, String> {
+        let delimiter = match delimiter {
+            Some(d) => Some(parse_delimiter(d)?),
+            None => None,
+        };
+        Ok(Fields {
+            delimiter,
+            ranges: parse_ranges(ranges)?,
+        })
+    }
+
+    /// Returns the byte ranges of the selected fields in `s`. A field
+    /// includes the delimiter that follows it.
+    pub fn select(&self, s: &str) -> Vec<Range<usize>> {
+        self.select_with(s, false)
+    }
+
+    /// Like `select` but drops the delimiter after the last field, which
+    /// looks odd when the selected fields are shown.
+    pub fn select_for_display(&self, s: &str) -> Vec<Range<usize>> {
+        self.select_with(s, true)
+    }
+
+    fn select_with(&self, s: &str, trims_last: bool) -> Vec<Range<usize>> {
+        let fields = self.split(s);
+        let len = fields.len() as isize;
+        let resolve = |i: isize| if i < 0 { len + i } else { i - 1 };
+        let mut selected = Vec::new();
+        let mut last_content_end = 0;
+        for range in &self.ranges {
+            let start = range.start.map(resolve).unwrap_or(0).max(0);
+            let end = range.end.map(resolve).unwrap_or(len - 1).min(len - 1);
+            for i in start..=end {
+                let (ref field, content_end) = fields[i as usize];
+                selected.push(field.clone());
+                last_content_end = content_end;
+            }
+        }
+        if trims_last {
+            if let Some(last) = selected.last_mut() {
+                last.end = last_content_end;
+            }
+        }
+        selected
+    }
+
+    // Returns the range of each field and where its content ends, i.e. where
+    // the delimiter starts.
+    fn split(&self, s: &str) -> Vec<(Range<usize>, usize)> {
+        let mut fields = Vec::new();
+        let mut start = 0;
+        let mut content_end = s.len();
+        match self.delimiter {
+            Some(ref delimiter) => {
+                for m in delimiter.find_iter(s) {
+                    if m.end() > start {
+                        fields.push((start..m.end(), m.start()));
+                        start = m.end();
+                    }
+                }
+            }
+            None => {
+                let mut in_whitespace = false;
+                let mut seen_word = false;
+                for (i, ch) in s.char_indices() {
+                    if ch.is_whitespace() {
+                        if !in_whitespace {
+                            content_end = i;
+                        }
+                        in_whitespace = true;
+                    } else {
+                        if in_whitespace && seen_word {
+                            fields.push((start..i, content_end));
+                            start = i;
+                        }
+                        in_whitespace = false;
+                        seen_word = true;
+                        content_end = s.len();
+                    }
+                }
+            }
+        }
+        if start < s.len() {
+            fields.push((start..s.len(), content_end.max(start)));
+        }
+        fields
+    }
+}
+
+pub fn validate_delimiter(s: String) -> Result<(), String> {
+    parse_delimiter(&s).map(|_| ())
+}
+
+pub fn validate_ranges(s: String) -> Result<(), String> {
+    parse_ranges(&s).map(|_| ())
+}
+
+fn parse_delimiter(s: &str) -> Result<Regex, String> {
+    Regex::new(s).map_err(|e| e.to_string())
+}
+
+// Parses comma-separated field ranges: `N`, `N..`, `..M`, `N..M` or `..`.
+fn parse_ranges(s: &str) -> Result<Vec<FieldRange>, String> {
+    s.split(',').map(parse_range).collect()
+}
+
+fn parse_range(s: &str) -> Result<FieldRange, String> {
+    let parse_index = |t: &str| -> Result<Option<isize>, String> {
+        if t.is_empty() {
+            return Ok(None);
+        }
+        match t.parse::<isize>() {
+            Ok(0) | Err(_) => Err(format!("invalid field index: {}", t)),
+            Ok(i) => Ok(Some(i)),
+        }
+    };
+    match s.find("..") {
+        Some(i) => Ok(FieldRange {
+            start: parse_index(&s[..i])?,
+            end: parse_index(&s[i + 2..])?,
+        }),
+        None if s.is_empty() => Err("empty field range".to_owned()),
+        None => {
+            let i = parse_index(s)?;
+            Ok(FieldRange { start: i, end: i })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn select<'a>(delimiter: Option<&str>, ranges: &str, s: &'a str) -> Vec<&'a str> {
+        let fields = Fields::new(delimiter, ranges).unwrap();
+        fields
+            .select(s)
+            .into_iter()
+            .map(|range| &s[range])
+            .collect()
+    }
+
+    #[test]
+    fn whitespace_separates_fields_by_default() {
+        assert_eq!(select(None, "2", "  foo  bar baz"), vec!["bar "]);
+        assert_eq!(
+            select(None, "1,-1", "  foo  bar baz"),
+            vec!["  foo  ", "baz"]
+        );
+        assert_eq!(select(None, "2..", "foo bar baz"), vec!["bar ", "baz"]);
+    }
+
+    #[test]
+    fn delimiter_is_a_regex() {
+        assert_eq!(select(Some(","), "2", "a,b,c"), vec!["b,"]);
+        assert_eq!(select(Some("[,;]"), "..2", "a;b,c"), vec!["a;", "b,"]);
+        assert_eq!(select(Some(r"\s*:\s*"), "-1", "a : b"), vec!["b"]);
+    }
+
+    #[test]
+    fn invalid_delimiter_is_an_error() {
+        assert!(validate_delimiter("[,".to_owned()).is_err());
+        assert!(Fields::new(Some("(a"), "1").is_err());
+        assert!(validate_delimiter(r"\|".to_owned()).is_ok());
+    }
+
+    #[test]
+    fn invalid_ranges_are_errors() {
+        for ranges in ["0", "a", "1,", "1..x"].iter() {
+            assert!(validate_ranges(ranges.to_string()).is_err(), "{}", ranges);
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use std::cmp;
+use std::mem;
+use std::ops::Range;
+
+const BITS_PER_WORD: usize = 64;
+
+/// An ascending set of line indices. Results of short queries tend to be
+/// dense, so `compact` switches to runs or a bitmap when either is smaller
+/// than a plain list.
+#[derive(Debug)]
+pub enum IndexSet {
+    List(Vec<usize>),
+    Runs(Vec<Range<usize>>),
+    // Bit `i` of the set is at `words[(i - start) / 64]`.
+    Bitmap {
+        start: usize,
+        words: Vec<u64>,
+        len: usize,
+    },
+}
+
+impl IndexSet {
+    pub fn new() -> Self {
+        IndexSet::List(Vec::new())
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            IndexSet::List(indices) => indices.len(),
+            IndexSet::Runs(runs) => runs.iter().map(|run| run.len()).sum(),
+            IndexSet::Bitmap { len, .. } => *len,
+        }
+    }
+
+    pub fn last(&self) -> Option<usize> {
+        match self {
+            IndexSet::List(indices) => indices.last().cloned(),
+            IndexSet::Runs(runs) => runs.last().map(|run| run.end - 1),
+            IndexSet::Bitmap { start, words, .. } => {
+                let (word_index, word) = words.iter().enumerate().rev().find(|(_, &w)| w != 0)?;
+                let bit = BITS_PER_WORD - 1 - word.leading_zeros() as usize;
+                Some(start + word_index * BITS_PER_WORD + bit)
+            }
+        }
+    }
+
+    /// Appends `i`, which must be greater than any index in the set.
+    pub fn push(&mut self, i: usize) {
+        debug_assert!(self.last().map(|last| last < i).unwrap_or(true));
+        match self {
+            IndexSet::List(indices) => indices.push(i),
+            IndexSet::Runs(runs) => match runs.last_mut() {
+                Some(run) if run.end == i => run.end += 1,
+                _ => runs.push(i..i + 1),
+            },
+            IndexSet::Bitmap { start, words, len } => {
+                let offset = i - *start;
+                let word = offset / BITS_PER_WORD;
+                if word >= words.len() {
+                    words.resize(word + 1, 0);
+                }
+                words[word] |= 1 << (offset % BITS_PER_WORD);
+                *len += 1;
+            }
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        match self {
+            IndexSet::List(indices) => Iter::List(indices.iter()),
+            IndexSet::Runs(runs) => Iter::Runs {
+                runs: runs.iter(),
+                run: 0..0,
+            },
+            IndexSet::Bitmap { start, words, .. } => Iter::Bitmap {
+                start: *start,
+                words,
+                word_index: 0,
+                word: words.first().cloned().unwrap_or(0),
+            },
+        }
+    }
+
+    /// Returns the approximate number of bytes allocated for the set.
+    pub fn heap_size(&self) -> usize {
+        match self {
+            IndexSet::List(indices) => indices.capacity() * mem::size_of::<usize>(),
+            IndexSet::Runs(runs) => runs.capacity() * mem::size_of::<Range<usize>>(),
+            IndexSet::Bitmap { words, .. } => words.capacity() * mem::size_of::<u64>(),
+        }
+    }
+
+    /// Converts the set into whichever representation takes the least memory.
+    pub fn compact(&mut self) {
+        let len = self.len();
+        let num_runs = self.num_runs();
+        let (first, last) = match (self.iter().next(), self.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => {
+                *self = IndexSet::new();
+                return;
+            }
+        };
+        let num_words = (last - first) / BITS_PER_WORD + 1;
+        let list_size = len * mem::size_of::<usize>();
+        let runs_size = num_runs * mem::size_of::<Range<usize>>();
+        let bitmap_size = num_words * mem::size_of::<u64>();
+        let mut compacted = if bitmap_size < cmp::min(list_size, runs_size) {
+            IndexSet::Bitmap {
+                start: first,
+                words: Vec::with_capacity(num_words),
+                len: 0,
+            }
+        } else if runs_size < list_size {
+            IndexSet::Runs(Vec::with_capacity(num_runs))
+        } else {
+            IndexSet::List(Vec::with_capacity(len))
+        };
+        for i in self.iter() {
+            compacted.push(i);
+        }
+        *self = compacted;
+    }
+
+    fn num_runs(&self) -> usize {
+        let mut num_runs = 0;
+        let mut prev = None;
+        for i in self.iter() {
+            if prev.map(|prev| prev + 1 != i).unwrap_or(true) {
+                num_runs += 1;
+            }
+            prev = Some(i);
+        }
+        num_runs
+    }
+}
+
+impl Default for IndexSet {
+    fn default() -> Self {
+        IndexSet::new()
+    }
+}
+
+impl From<Vec<usize>> for IndexSet {
+    fn from(indices: Vec<usize>) -> Self {
+        IndexSet::List(indices)
+    }
+}
+
+pub enum Iter<'a> {
+    List(::std::slice::Iter<'a, usize>),
+    Runs {
+        runs: ::std::slice::Iter<'a, Range<usize>>,
+        run: Range<usize>,
+    },
+    Bitmap {
+        start: usize,
+        words: &'a [u64],
+        word_index: usize,
+        word: u64,
+    },
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        match self {
+            Iter::List(iter) => iter.next().cloned(),
+            Iter::Runs { runs, run } => loop {
+                if let Some(i) = run.next() {
+                    return Some(i);
+                }
+                *run = runs.next()?.clone();
+            },
+            Iter::Bitmap {
+                start,
+                words,
+                word_index,
+                word,
+            } => loop {
+                if *word != 0 {
+                    let bit = word.trailing_zeros() as usize;
+                    *word &= *word - 1;
+                    return Some(*start + *word_index * BITS_PER_WORD + bit);
+                }
+                *word_index += 1;
+                *word = *words.get(*word_index)?;
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compacted(indices: &[usize]) -> IndexSet {
+        let mut set = IndexSet::from(indices.to_vec());
+        set.compact();
+        set
+    }
+
+    fn check_round_trip(set: &IndexSet, indices: &[usize]) {
+        assert_eq!(set.iter().collect::<Vec<_>>(), indices);
+        assert_eq!(set.len(), indices.len());
+        assert_eq!(set.last(), indices.last().cloned());
+    }
+
+    #[test]
+    fn sparse_indices_stay_a_list() {
+        let indices = [3, 1000, 5000, 90000];
+        let set = compacted(&indices);
+        assert!(matches!(set, IndexSet::List(_)));
+        check_round_trip(&set, &indices);
+        assert_eq!(set.heap_size(), 4 * mem::size_of::<usize>());
+    }
+
+    #[test]
+    fn long_runs_become_runs() {
+        let indices: Vec<usize> = (10..1000).chain(100_000..101_000).collect();
+        let set = compacted(&indices);
+        assert!(matches!(set, IndexSet::Runs(ref runs) if runs.len() == 2));
+        check_round_trip(&set, &indices);
+        assert_eq!(set.heap_size(), 2 * mem::size_of::<Range<usize>>());
+    }
+
+    #[test]
+    fn dense_scattered_indices_become_a_bitmap() {
+        let indices: Vec<usize> = (100..1100).filter(|i| i % 3 != 0).collect();
+        let set = compacted(&indices);
+        assert!(matches!(set, IndexSet::Bitmap { start: 100, .. }));
+        check_round_trip(&set, &indices);
+        assert_eq!(set.heap_size(), (999 / 64 + 1) * mem::size_of::<u64>());
+    }
+
+    #[test]
+    fn bitmap_spans_word_boundaries() {
+        let indices = [7, 8, 70, 71, 127, 128, 129, 191, 192];
+        let mut set = IndexSet::Bitmap {
+            start: 7,
+            words: Vec::new(),
+            len: 0,
+        };
+        for &i in indices.iter() {
+            set.push(i);
+        }
+        check_round_trip(&set, &indices);
+    }
+
+    #[test]
+    fn compacted_set_can_be_pushed_to() {
+        for indices in [
+            vec![1, 100, 10_000],
+            (0..500).collect(),
+            (0..500).filter(|i| i % 2 == 0).collect(),
+        ]
+        .iter()
+        {
+            let mut set = compacted(indices);
+            set.push(20_000);
+            set.push(20_001);
+            let mut expected = indices.clone();
+            expected.extend([20_000, 20_001].iter());
+            check_round_trip(&set, &expected);
+        }
+    }
+
+    #[test]
+    fn empty_set_compacts_to_empty_list() {
+        let set = compacted(&[]);
+        assert!(matches!(set, IndexSet::List(ref indices) if indices.is_empty()));
+        check_round_trip(&set, &[]);
+    }
+}
@@ -1,5 +0,0 @@
-use std::sync::Arc;
-
-use crate::line::Line;
-
-pub type Item = Arc<Line>;
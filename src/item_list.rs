@@ -76,6 +76,12 @@ impl ItemList {
         }
     }
 
+    /// Unmarks all lines, e.g. as their indices no longer point to the same
+    /// lines.
+    pub fn clear_marks(&mut self) {
+        self.marked_line_indices.clear();
+    }
+
     pub fn set_line_indices(&mut self, line_indices: Vec<usize>) {
         self.set_line_indices_with_box(Box::new(line_indices));
     }
@@ -171,7 +177,7 @@ impl Indices for Range<usize> {
     }
 
     fn len(&self) -> usize {
-        <Self as ExactSizeIterator>::len(&self)
+        <Self as ExactSizeIterator>::len(self)
     }
 }
 
@@ -185,6 +191,6 @@ impl Indices for Vec<usize> {
     }
 
     fn len(&self) -> usize {
-        Vec::len(&self)
+        Vec::len(self)
     }
 }
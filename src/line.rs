@@ -1,45 +1,249 @@
-pub struct Line {
-    bytes: Vec<u8>,
-    chars: Vec<char>,
-    lossy_string: Option<String>,
+use encoding_rs::Encoding;
+use std::borrow::Cow;
+use std::str;
+
+use crate::ansi::{self, Styles};
+use crate::field::Fields;
+
+/// A line in `LineStorage`.
+#[derive(Clone, Copy)]
+pub struct Line<'a> {
+    bytes: &'a [u8],
+    encoding: Option<&'static Encoding>,
+    is_ansi: bool,
+    is_utf8: bool,
+    origin: usize,
 }
 
-impl Line {
-    pub fn new(bytes: Vec<u8>) -> Line {
-        match String::from_utf8(bytes) {
-            Ok(string) => {
-                let chars = string.chars().collect();
-                Line {
-                    bytes: string.into_bytes(),
-                    chars,
-                    lossy_string: None,
-                }
-            }
-            Err(error) => {
-                let bytes = error.into_bytes();
-                let lossy = String::from_utf8_lossy(&bytes).into_owned();
-                let chars = lossy.chars().collect();
-                Line {
-                    bytes,
-                    chars,
-                    lossy_string: Some(lossy),
-                }
+impl<'a> Line<'a> {
+    /// `encoding` is the one `bytes` are decoded from, or `None` for UTF-8.
+    /// `is_ansi` tells if escape sequences in `bytes` are parsed. `is_utf8`
+    /// must be true only if `bytes` is valid UTF-8.
+    pub fn new(
+        bytes: &'a [u8],
+        encoding: Option<&'static Encoding>,
+        is_ansi: bool,
+        is_utf8: bool,
+        origin: usize,
+    ) -> Self {
+        Line {
+            bytes,
+            encoding,
+            is_ansi,
+            is_utf8,
+            origin,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Returns the encoding the line is decoded from, or `None` for UTF-8.
+    pub fn encoding(self) -> Option<&'static Encoding> {
+        self.encoding
+    }
+
+    /// Returns the index of the input the line came from.
+    pub fn origin(self) -> usize {
+        self.origin
+    }
+
+    /// Returns the line as a string decoded from its encoding, replacing
+    /// invalid sequences with U+FFFD and removing escape sequences if they
+    /// are parsed.
+    pub fn to_str(self) -> Cow<'a, str> {
+        self.to_styled().0
+    }
+
+    /// Returns the line as `to_str` does, along with the styles its SGR
+    /// sequences set.
+    pub fn to_styled(self) -> (Cow<'a, str>, Styles) {
+        let s = match self.encoding {
+            Some(encoding) => encoding.decode_without_bom_handling(self.bytes).0,
+            // Validated when the line was stored.
+            None if self.is_utf8 => Cow::Borrowed(unsafe { str::from_utf8_unchecked(self.bytes) }),
+            None => String::from_utf8_lossy(self.bytes),
+        };
+        if !self.is_ansi {
+            return (s, Vec::new());
+        }
+        match s {
+            Cow::Borrowed(s) => ansi::parse(s),
+            Cow::Owned(s) => {
+                let (text, styles) = ansi::parse(&s);
+                (Cow::Owned(text.into_owned()), styles)
             }
         }
     }
+}
 
-    pub fn as_bytes(&self) -> &[u8] {
-        &self.bytes
+/// A line as shown in the list. `positions` and `styles` are byte offsets in
+/// `text`.
+pub struct DisplayLine {
+    pub positions: Vec<usize>,
+    pub styles: Styles,
+    pub text: String,
+}
+
+/// Returns the text to show for the line `s`, which is the `fields` of it if
+/// any, along with `positions` of matched chars and `styles` (byte offsets in
+/// `s`) mapped into the text. Newlines, which only lines read with `--read0`
+/// contain, are shown as `␊` so that a line takes a single row.
+pub fn to_display(
+    s: &str,
+    fields: Option<&Fields>,
+    positions: Vec<usize>,
+    styles: Styles,
+) -> DisplayLine {
+    let fields = match fields {
+        Some(fields) => fields,
+        None => return mark_newlines(s, positions, styles),
+    };
+    let mut text = String::new();
+    let mut display_positions = Vec::new();
+    let mut display_styles: Styles = Vec::new();
+    for range in fields.select_for_display(s) {
+        let first = positions.partition_point(|&i| i < range.start);
+        let last = positions.partition_point(|&i| i < range.end);
+        let offset = text.len();
+        display_positions.extend(
+            positions[first..last]
+                .iter()
+                .map(|i| offset + i - range.start),
+        );
+        // The style in effect where the field starts, then the ones set in
+        // it.
+        let first = styles.partition_point(|&(i, _)| i <= range.start);
+        let last = styles.partition_point(|&(i, _)| i < range.end);
+        let style = first
+            .checked_sub(1)
+            .map(|i| styles[i].1)
+            .unwrap_or_default();
+        let last_style = display_styles
+            .last()
+            .map(|&(_, style)| style)
+            .unwrap_or_default();
+        if style != last_style {
+            display_styles.push((offset, style));
+        }
+        display_styles.extend(
+            styles[first..last]
+                .iter()
+                .map(|&(i, style)| (offset + i - range.start, style)),
+        );
+        text.push_str(&s[range]);
     }
+    mark_newlines(&text, display_positions, display_styles)
+}
 
-    pub fn as_chars(&self) -> &[char] {
-        &self.chars
+// Replaces newlines in `s` with `␊`, which is longer in bytes, so
+// `positions` and `styles` are shifted too.
+fn mark_newlines(s: &str, positions: Vec<usize>, styles: Styles) -> DisplayLine {
+    if !s.contains('\n') {
+        return DisplayLine {
+            positions,
+            styles,
+            text: s.to_owned(),
+        };
     }
+    let mut text = String::with_capacity(s.len() + 8);
+    let mut marked_positions = Vec::with_capacity(positions.len());
+    let mut marked_styles = Vec::with_capacity(styles.len());
+    let mut positions = positions.into_iter().peekable();
+    let mut styles = styles.into_iter().peekable();
+    for (i, c) in s.char_indices() {
+        if positions.peek() == Some(&i) {
+            positions.next();
+            marked_positions.push(text.len());
+        }
+        if let Some(&(_, style)) = styles.peek().filter(|&&(offset, _)| offset == i) {
+            styles.next();
+            marked_styles.push((text.len(), style));
+        }
+        text.push(if c == '\n' { '\u{240a}' } else { c });
+    }
+    DisplayLine {
+        positions: marked_positions,
+        styles: marked_styles,
+        text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ansi::Style;
 
-    pub fn as_str(&self) -> &str {
-        match self.lossy_string {
-            Some(ref s) => s,
-            None => std::str::from_utf8(&self.bytes).unwrap(),
+    fn bold() -> Style {
+        Style {
+            is_bold: true,
+            ..Style::default()
         }
     }
+
+    fn fg(color: u8) -> Style {
+        Style {
+            fg: Some(color),
+            ..Style::default()
+        }
+    }
+
+    fn fields(ranges: &str) -> Fields {
+        Fields::new(Some(","), ranges).unwrap()
+    }
+
+    #[test]
+    fn whole_line_is_shown_as_is() {
+        let line = to_display("abc", None, vec![1], vec![(2, bold())]);
+        assert_eq!(line.text, "abc");
+        assert_eq!(line.positions, [1]);
+        assert_eq!(line.styles, [(2, bold())]);
+    }
+
+    #[test]
+    fn positions_and_styles_move_into_fields() {
+        let styles = vec![(0, bold()), (4, fg(1)), (7, Style::default())];
+        let line = to_display("ab,cd,ef", Some(&fields("2..3")), vec![1, 3, 7], styles);
+        assert_eq!(line.text, "cd,ef");
+        // The match in the first field is not shown, and the style the
+        // second field starts in is carried over.
+        assert_eq!(line.positions, [0, 4]);
+        assert_eq!(
+            line.styles,
+            [(0, bold()), (1, fg(1)), (4, Style::default())]
+        );
+    }
+
+    #[test]
+    fn fields_apart_are_joined() {
+        let styles = vec![(4, fg(1))];
+        let line = to_display("ab,cd,ef", Some(&fields("1,3")), vec![0, 4, 6], styles);
+        assert_eq!(line.text, "ab,ef");
+        assert_eq!(line.positions, [0, 3]);
+        assert_eq!(line.styles, [(3, fg(1))]);
+    }
+
+    #[test]
+    fn newlines_around_a_match_are_marked() {
+        let line = to_display("x\ny\nz", None, vec![2], vec![(2, bold()), (3, fg(1))]);
+        assert_eq!(line.text, "x␊y␊z");
+        assert_eq!(line.positions, [4]);
+        assert_eq!(line.styles, [(4, bold()), (5, fg(1))]);
+        let line = to_display("x\ny\nz", None, vec![1, 4], Vec::new());
+        assert_eq!(line.positions, [1, 8]);
+    }
+
+    #[test]
+    fn newlines_in_fields_are_marked() {
+        let line = to_display(
+            "ab,c\nd,e",
+            Some(&fields("2")),
+            vec![3, 5],
+            vec![(5, bold())],
+        );
+        assert_eq!(line.text, "c␊d");
+        assert_eq!(line.positions, [0, 4]);
+        assert_eq!(line.styles, [(4, bold())]);
+    }
 }
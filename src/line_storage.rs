@@ -1,31 +1,714 @@
-use std::sync::Arc;
+use encoding_rs::Encoding;
+use memchr::memchr;
+use std::str;
+use std::sync::{Arc, RwLock};
 
-use crate::item::Item;
+use crate::config::Config;
 use crate::line::Line;
+use crate::mmap::Mmap;
+use crate::unique::{Deduplicator, Verdict};
 
-#[derive(Clone)]
+/// Stores lines back to back in a buffer per input, or indexes them in place
+/// in a mapped file, so that each of them costs little more than its bytes.
 pub struct LineStorage {
-    lines: Vec<Arc<Line>>,
+    // Tells duplicates of lines with `--unique` in a chunk, which may be of
+    // lines already put to `parent`.
+    deduplicator: Option<Deduplicator>,
+    // The byte lines end with, usually a newline.
+    delimiter: u8,
+    // Where each line ends in the bytes of its source, including its
+    // delimiter if any.
+    ends: Vec<usize>,
+    // The index in the whole input of the first line, which is not 0 for a
+    // chunk put to `parent` before.
+    first_line_index: usize,
+    // The first lines of the input, which are shown above the list but not
+    // searched.
+    header: Vec<String>,
+    header_len: usize,
+    header_lines_left: usize,
+    is_ansi: bool,
+    non_utf8_line_indices: Vec<usize>,
+    num_duplicates: usize,
+    num_removed: usize,
+    // The storage a chunk is put to.
+    parent: Option<Arc<RwLock<LineStorage>>>,
+    // A bitmap of lines removed as duplicates, by their indices in the whole
+    // input.
+    removed: Vec<u64>,
+    sources: Vec<Source>,
+}
+
+// An input the lines come from, such as a file.
+struct Source {
+    bytes: Bytes,
+    // The encoding lines are decoded from, or `None` for UTF-8.
+    encoding: Option<&'static Encoding>,
+    first_line_index: usize,
+    origin: usize,
+    // Where the first line starts, which is not 0 for a chunk of a mapped
+    // file.
+    start: usize,
+}
+
+enum Bytes {
+    Owned(Vec<u8>),
+    Mapped(Arc<Mmap>),
 }
 
 impl LineStorage {
-    pub fn new() -> Self {
-        LineStorage { lines: Vec::new() }
+    pub fn new(config: &Config) -> Self {
+        LineStorage {
+            deduplicator: None,
+            delimiter: config.input_delimiter(),
+            ends: Vec::new(),
+            first_line_index: 0,
+            header: Vec::new(),
+            header_len: config.header_lines(),
+            header_lines_left: config.header_lines(),
+            is_ansi: config.ansi(),
+            non_utf8_line_indices: Vec::new(),
+            num_duplicates: 0,
+            num_removed: 0,
+            parent: None,
+            removed: Vec::new(),
+            sources: Vec::new(),
+        }
+    }
+
+    /// Returns a storage for chunks of lines to be put to `parent`, which
+    /// tells duplicates of the lines of both with `--unique`.
+    pub fn new_chunk(config: &Config, parent: Arc<RwLock<LineStorage>>) -> Self {
+        let mut chunk = LineStorage::new(config);
+        if let Some(occurrence) = config.unique() {
+            chunk.deduplicator = Some(Deduplicator::new(occurrence));
+            chunk.parent = Some(parent);
+        }
+        chunk
+    }
+
+    /// Starts a source the following lines come from. `origin` tells the
+    /// input apart, e.g. the index of the file, and `encoding` is the one of
+    /// its lines unless they are UTF-8. Lines of `mmap` are added by
+    /// `index_mapped_lines` and the others by `push`, which continue the last
+    /// source if it is read from the same origin in the same encoding.
+    pub fn add_source(
+        &mut self,
+        origin: usize,
+        encoding: Option<&'static Encoding>,
+        mmap: Option<Arc<Mmap>>,
+    ) {
+        if let Some(Source {
+            bytes: Bytes::Owned(_),
+            encoding: last_encoding,
+            origin: last_origin,
+            ..
+        }) = self.sources.last()
+        {
+            if mmap.is_none() && *last_origin == origin && *last_encoding == encoding {
+                return;
+            }
+        }
+        let bytes = match mmap {
+            Some(mmap) => Bytes::Mapped(mmap),
+            None => Bytes::Owned(Vec::new()),
+        };
+        self.sources.push(Source {
+            bytes,
+            encoding,
+            first_line_index: self.len(),
+            origin,
+            start: 0,
+        });
+    }
+
+    /// Removes all lines and header lines but keeps adding to the last
+    /// source, numbering lines after the removed ones.
+    pub fn clear(&mut self) {
+        let end = self.end();
+        self.first_line_index += self.len();
+        if let Some(mut source) = self.sources.pop() {
+            match source.bytes {
+                Bytes::Owned(ref mut bytes) => bytes.clear(),
+                Bytes::Mapped(_) => source.start = end,
+            }
+            source.first_line_index = 0;
+            self.sources.clear();
+            self.sources.push(source);
+        }
+        self.ends.clear();
+        self.header.clear();
+        self.non_utf8_line_indices.clear();
+        self.num_duplicates = 0;
+        self.num_removed = 0;
+        self.removed.clear();
+    }
+
+    pub fn get_many_unchecked(&self, indices: Vec<usize>) -> Vec<Line<'_>> {
+        indices.iter().map(|i| self.get_unchecked(*i)).collect()
+    }
+
+    pub fn get_unchecked(&self, index: usize) -> Line<'_> {
+        let source = self.source_of(index);
+        let start = if index == source.first_line_index {
+            source.start
+        } else {
+            self.ends[index - 1]
+        };
+        let mut line = &source.bytes.as_slice()[start..self.ends[index]];
+        // Read lines always end with the delimiter, while the last mapped one
+        // may not. The byte may be part of the last char of a UTF-16 line.
+        let is_read = matches!(source.bytes, Bytes::Owned(_));
+        if is_read || line.last() == Some(&self.delimiter) {
+            line = &line[..line.len() - 1];
+        }
+        let is_utf8 = self.non_utf8_line_indices.binary_search(&index).is_err();
+        Line::new(line, source.encoding, self.is_ansi, is_utf8, source.origin)
     }
 
-    pub fn get_many_unchecked(&self, indices: Vec<usize>) -> Vec<Item> {
-        indices.iter().map(|i| self.lines[*i].clone()).collect()
+    /// Returns the header lines taken so far.
+    pub fn header(&self) -> &[String] {
+        &self.header
     }
 
-    pub fn iter<'a>(&'a self) -> ::std::slice::Iter<'a, Arc<Line>> {
-        self.lines.iter()
+    /// Indexes up to `max_lines` lines of the mapped file following the ones
+    /// already indexed and returns true if any are left.
+    pub fn index_mapped_lines(&mut self, max_lines: usize) -> bool {
+        let mut start = self.end();
+        let first = self.len();
+        let bytes = match self.sources.last().map(|source| &source.bytes) {
+            Some(Bytes::Mapped(mmap)) => mmap.clone(),
+            _ => panic!("lines are not mapped"),
+        };
+        // Header lines come first, so none of the lines of the source are
+        // indexed yet.
+        while self.header_lines_left > 0 && start < bytes.len() {
+            let end = match memchr(self.delimiter, &bytes[start..]) {
+                Some(i) => start + i + 1,
+                None => bytes.len(),
+            };
+            let mut line = &bytes[start..end];
+            if line.last() == Some(&self.delimiter) {
+                line = &line[..line.len() - 1];
+            }
+            self.take_header_line(line);
+            start = end;
+            self.sources.last_mut().unwrap().start = start;
+        }
+        let mut end = start;
+        for _ in 0..max_lines {
+            if end == bytes.len() {
+                break;
+            }
+            end = match memchr(self.delimiter, &bytes[end..]) {
+                Some(i) => end + i + 1,
+                None => bytes.len(),
+            };
+            self.ends.push(end);
+        }
+        if self.deduplicator.is_some() {
+            for i in first..self.len() {
+                let line_start = if i == first { start } else { self.ends[i - 1] };
+                let mut line = &bytes[line_start..self.ends[i]];
+                if line.last() == Some(&self.delimiter) {
+                    line = &line[..line.len() - 1];
+                }
+                // Mapped lines stay where they are, so a dropped one is removed.
+                let index = self.first_line_index + i;
+                if self.deduplicate(line, index) == Verdict::Drop {
+                    self.remove(index);
+                }
+            }
+        }
+        // Neither a newline nor NUL splits a UTF-8 sequence, so each line of
+        // a valid block is valid too. Lines in other encodings are decoded
+        // anyway.
+        let is_utf8 = self.sources.last().unwrap().encoding.is_none();
+        if is_utf8 && str::from_utf8(&bytes[start..end]).is_err() {
+            for i in first..self.len() {
+                if str::from_utf8(self.get_unchecked(i).as_bytes()).is_err() {
+                    self.non_utf8_line_indices.push(i);
+                }
+            }
+        }
+        end < bytes.len()
+    }
+
+    /// Returns true if the line was removed as a duplicate.
+    pub fn is_removed(&self, index: usize) -> bool {
+        self.removed
+            .get(index / 64)
+            .is_some_and(|word| word >> (index % 64) & 1 == 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ends.is_empty()
     }
 
     pub fn len(&self) -> usize {
-        self.lines.len()
+        self.ends.len()
+    }
+
+    /// Returns the length in bytes of the line at `index` as read.
+    pub fn line_len(&self, index: usize) -> usize {
+        self.get_unchecked(index).as_bytes().len()
+    }
+
+    /// Returns the number of lines dropped or removed as duplicates.
+    pub fn num_duplicates(&self) -> usize {
+        self.num_duplicates
+    }
+
+    /// Returns the number of lines removed as duplicates, which are still
+    /// counted in `len`.
+    pub fn num_removed(&self) -> usize {
+        self.num_removed
+    }
+
+    pub fn push(&mut self, line: &[u8]) {
+        if self.header_lines_left > 0 {
+            self.take_header_line(line);
+            return;
+        }
+        if self.deduplicate(line, self.first_line_index + self.len()) == Verdict::Drop {
+            return;
+        }
+        let (bytes, encoding) = match self.sources.last_mut() {
+            Some(Source {
+                bytes: Bytes::Owned(bytes),
+                encoding,
+                ..
+            }) => (bytes, *encoding),
+            _ => panic!("lines can only be pushed to a read source"),
+        };
+        if encoding.is_none() && str::from_utf8(line).is_err() {
+            self.non_utf8_line_indices.push(self.ends.len());
+        }
+        bytes.extend_from_slice(line);
+        bytes.push(self.delimiter);
+        self.ends.push(bytes.len());
+    }
+
+    /// Appends the lines of `chunk`. A first source of `chunk` with the same
+    /// origin and encoding as the last one of `self` continues it.
+    pub fn put_chunk(&mut self, chunk: &LineStorage) {
+        let len = self.len();
+        for (i, chunk_source) in chunk.sources.iter().enumerate() {
+            let continues = i == 0
+                && self
+                    .sources
+                    .last()
+                    .map(|source| (source.origin, source.encoding))
+                    == Some((chunk_source.origin, chunk_source.encoding));
+            if !continues {
+                let bytes = match chunk_source.bytes {
+                    Bytes::Owned(_) => Bytes::Owned(Vec::new()),
+                    Bytes::Mapped(ref mmap) => Bytes::Mapped(mmap.clone()),
+                };
+                self.sources.push(Source {
+                    bytes,
+                    encoding: chunk_source.encoding,
+                    first_line_index: len + chunk_source.first_line_index,
+                    origin: chunk_source.origin,
+                    start: chunk_source.start,
+                });
+            }
+            let base = match (
+                &mut self.sources.last_mut().unwrap().bytes,
+                &chunk_source.bytes,
+            ) {
+                (Bytes::Owned(bytes), Bytes::Owned(chunk_bytes)) => {
+                    let base = bytes.len();
+                    bytes.extend_from_slice(chunk_bytes);
+                    base
+                }
+                (Bytes::Mapped(_), Bytes::Mapped(_)) => 0,
+                _ => panic!("cannot mix mapped and read lines of a source"),
+            };
+            let end = chunk
+                .sources
+                .get(i + 1)
+                .map(|source| source.first_line_index)
+                .unwrap_or_else(|| chunk.len());
+            let ends = &chunk.ends[chunk_source.first_line_index..end];
+            self.ends.extend(ends.iter().map(|end| base + end));
+        }
+        self.non_utf8_line_indices
+            .extend(chunk.non_utf8_line_indices.iter().map(|i| len + i));
+        self.header.extend(chunk.header.iter().cloned());
+        if self.removed.len() < chunk.removed.len() {
+            self.removed.resize(chunk.removed.len(), 0);
+        }
+        for (word, chunk_word) in self.removed.iter_mut().zip(chunk.removed.iter()) {
+            *word |= chunk_word;
+        }
+        self.num_duplicates += chunk.num_duplicates;
+        self.num_removed += chunk.num_removed;
+    }
+
+    /// Removes all lines and starts over as if the input is read again: the
+    /// header lines are taken again and the lines seen for `--unique` are
+    /// forgotten.
+    pub fn reset(&mut self) {
+        self.clear();
+        self.first_line_index = 0;
+        self.header_lines_left = self.header_len;
+        if let Some(ref mut deduplicator) = self.deduplicator {
+            deduplicator.clear();
+        }
+    }
+
+    // Tells if `line` at `index` in the whole input is a duplicate with
+    // `--unique`, removing the kept line it replaces if any.
+    fn deduplicate(&mut self, line: &[u8], index: usize) -> Verdict {
+        let mut deduplicator = match self.deduplicator.take() {
+            Some(deduplicator) => deduplicator,
+            None => return Verdict::Keep,
+        };
+        let verdict = deduplicator.put(line, index, |i| self.is_line_at(i, line));
+        self.deduplicator = Some(deduplicator);
+        match verdict {
+            Verdict::Keep => {}
+            Verdict::Drop => self.num_duplicates += 1,
+            Verdict::Replace(i) => {
+                self.num_duplicates += 1;
+                self.remove(i);
+            }
+        }
+        verdict
+    }
+
+    // Returns true if the line at `index` in the whole input, which is in
+    // `parent` if put to it, has the bytes of `line`.
+    fn is_line_at(&self, index: usize, line: &[u8]) -> bool {
+        if index >= self.first_line_index {
+            return self.get_unchecked(index - self.first_line_index).as_bytes() == line;
+        }
+        let parent = self.parent.as_ref().unwrap().read().unwrap();
+        parent.get_unchecked(index).as_bytes() == line
+    }
+
+    // Removes the line at `index` in the whole input as a duplicate.
+    fn remove(&mut self, index: usize) {
+        if self.removed.len() <= index / 64 {
+            self.removed.resize(index / 64 + 1, 0);
+        }
+        self.removed[index / 64] |= 1 << (index % 64);
+        self.num_removed += 1;
+    }
+
+    // Keeps `line` of the last source as a header line.
+    fn take_header_line(&mut self, line: &[u8]) {
+        let source = self.sources.last().unwrap();
+        let is_utf8 = str::from_utf8(line).is_ok();
+        let line = Line::new(line, source.encoding, self.is_ansi, is_utf8, source.origin);
+        self.header.push(line.to_str().into_owned());
+        self.header_lines_left -= 1;
+    }
+
+    // Returns where the last line ends in the bytes of the last source.
+    fn end(&self) -> usize {
+        match self.sources.last() {
+            Some(source) if self.len() > source.first_line_index => *self.ends.last().unwrap(),
+            Some(source) => source.start,
+            None => 0,
+        }
+    }
+
+    fn source_of(&self, index: usize) -> &Source {
+        let i = self
+            .sources
+            .partition_point(|source| source.first_line_index <= index);
+        &self.sources[i - 1]
+    }
+}
+
+impl Bytes {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Bytes::Owned(bytes) => bytes,
+            Bytes::Mapped(mmap) => mmap,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line_storage(args: &[&str]) -> LineStorage {
+        LineStorage::new(&Config::with_test_args(args))
+    }
+
+    fn lines(line_storage: &LineStorage) -> Vec<(usize, String)> {
+        (0..line_storage.len())
+            .map(|i| {
+                let line = line_storage.get_unchecked(i);
+                (line.origin(), line.to_str().into_owned())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pushed_lines_are_stored_back_to_back() {
+        let mut storage = line_storage(&[]);
+        storage.add_source(0, None, None);
+        for line in ["foo", "", "bar baz"].iter() {
+            storage.push(line.as_bytes());
+        }
+        assert_eq!(storage.len(), 3);
+        assert_eq!(storage.get_unchecked(0).as_bytes(), b"foo");
+        assert_eq!(storage.get_unchecked(1).as_bytes(), b"");
+        assert_eq!(storage.get_unchecked(2).as_bytes(), b"bar baz");
+        assert_eq!(storage.line_len(2), 7);
+    }
+
+    #[test]
+    fn lines_may_contain_newlines_with_read0() {
+        let mut storage = line_storage(&["--read0"]);
+        storage.add_source(0, None, None);
+        storage.push(b"a\n");
+        storage.push(b"b");
+        assert_eq!(storage.get_unchecked(0).as_bytes(), b"a\n");
+        assert_eq!(storage.get_unchecked(1).as_bytes(), b"b");
+    }
+
+    #[test]
+    fn invalid_utf8_lines_are_decoded_lossily() {
+        let mut storage = line_storage(&[]);
+        storage.add_source(0, None, None);
+        storage.push(b"ok");
+        storage.push(b"n\xffg");
+        storage.push("é".as_bytes());
+        assert_eq!(
+            lines(&storage),
+            vec![
+                (0, "ok".to_owned()),
+                (0, "n\u{fffd}g".to_owned()),
+                (0, "é".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn chunks_are_appended_and_cleared() {
+        let mut storage = line_storage(&[]);
+        let mut chunk = line_storage(&[]);
+        chunk.add_source(0, None, None);
+        chunk.push(b"a");
+        chunk.push(b"b\xff");
+        storage.put_chunk(&chunk);
+        chunk.clear();
+        assert!(chunk.is_empty());
+        chunk.push(b"c");
+        storage.put_chunk(&chunk);
+        assert_eq!(storage.len(), 3);
+        assert_eq!(storage.get_unchecked(1).as_bytes(), b"b\xff");
+        assert_eq!(storage.get_unchecked(2).as_bytes(), b"c");
+        assert_eq!(
+            lines(&storage)[1..],
+            [(0, "b\u{fffd}".to_owned()), (0, "c".to_owned())]
+        );
+    }
+
+    fn mapped(name: &str, contents: &[u8]) -> Arc<Mmap> {
+        let path = std::env::temp_dir().join(format!("eru-{}-{}", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        let mmap = Mmap::open(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        Arc::new(mmap)
+    }
+
+    #[test]
+    fn mapped_lines_are_indexed_in_batches() {
+        let mut storage = line_storage(&[]);
+        storage.add_source(0, None, Some(mapped("batches", b"a\nbb\n\nccc")));
+        assert!(storage.index_mapped_lines(2));
+        assert_eq!(storage.len(), 2);
+        assert!(!storage.index_mapped_lines(2));
+        assert_eq!(storage.len(), 4);
+        assert_eq!(storage.get_unchecked(2).as_bytes(), b"");
+        assert_eq!(storage.get_unchecked(3).as_bytes(), b"ccc");
+    }
+
+    #[test]
+    fn mapped_header_lines_are_skipped() {
+        let mut storage = line_storage(&["--header-lines", "2"]);
+        storage.add_source(0, None, Some(mapped("header", b"h1\nh2\na\nb\n")));
+        assert!(!storage.index_mapped_lines(10));
+        assert_eq!(storage.header(), ["h1", "h2"]);
+        assert_eq!(
+            lines(&storage),
+            vec![(0, "a".to_owned()), (0, "b".to_owned())]
+        );
+    }
+
+    #[test]
+    fn mapped_invalid_utf8_lines_are_found() {
+        let mut storage = line_storage(&[]);
+        storage.add_source(0, None, Some(mapped("utf8", b"ok\nn\xffg\n")));
+        storage.index_mapped_lines(10);
+        assert_eq!(storage.get_unchecked(1).to_str(), "n\u{fffd}g");
+    }
+
+    #[test]
+    fn chunks_of_several_origins_are_merged() {
+        let mut storage = line_storage(&[]);
+        let mut chunk = line_storage(&[]);
+        chunk.add_source(0, None, None);
+        chunk.push(b"a0");
+        storage.put_chunk(&chunk);
+        chunk.clear();
+        // The rest of origin 0, a mapped file and then origin 2.
+        chunk.push(b"a1");
+        chunk.add_source(1, None, Some(mapped("origins", b"b0\nb1\n")));
+        chunk.index_mapped_lines(10);
+        chunk.add_source(2, None, None);
+        chunk.push(b"c0");
+        storage.put_chunk(&chunk);
+        chunk.clear();
+        chunk.push(b"c1");
+        chunk.add_source(2, Some(encoding_rs::SHIFT_JIS), None);
+        chunk.push(b"\x83J\x83i");
+        storage.put_chunk(&chunk);
+        assert_eq!(
+            lines(&storage),
+            vec![
+                (0, "a0".to_owned()),
+                (0, "a1".to_owned()),
+                (1, "b0".to_owned()),
+                (1, "b1".to_owned()),
+                (2, "c0".to_owned()),
+                (2, "c1".to_owned()),
+                (2, "カナ".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn mapped_file_continues_across_chunks() {
+        let mut storage = line_storage(&[]);
+        let mut chunk = line_storage(&[]);
+        chunk.add_source(0, None, Some(mapped("continued", b"a\nb\nc\n")));
+        chunk.index_mapped_lines(2);
+        storage.put_chunk(&chunk);
+        chunk.clear();
+        chunk.index_mapped_lines(2);
+        storage.put_chunk(&chunk);
+        assert_eq!(
+            lines(&storage),
+            vec![
+                (0, "a".to_owned()),
+                (0, "b".to_owned()),
+                (0, "c".to_owned())
+            ]
+        );
+    }
+
+    // Returns the storage lines are put to and a chunk for it.
+    fn parent_and_chunk(args: &[&str]) -> (Arc<RwLock<LineStorage>>, LineStorage) {
+        let config = Config::with_test_args(args);
+        let parent = Arc::new(RwLock::new(LineStorage::new(&config)));
+        let chunk = LineStorage::new_chunk(&config, parent.clone());
+        (parent, chunk)
+    }
+
+    fn put_chunk(parent: &RwLock<LineStorage>, chunk: &mut LineStorage) {
+        parent.write().unwrap().put_chunk(chunk);
+        chunk.clear();
+    }
+
+    fn kept_lines(line_storage: &LineStorage) -> Vec<String> {
+        lines(line_storage)
+            .into_iter()
+            .enumerate()
+            .filter(|&(i, _)| !line_storage.is_removed(i))
+            .map(|(_, (_, s))| s)
+            .collect()
+    }
+
+    #[test]
+    fn first_duplicates_are_dropped_across_chunks() {
+        let (parent, mut chunk) = parent_and_chunk(&["--unique"]);
+        chunk.add_source(0, None, None);
+        for line in ["a", "b", "a"].iter() {
+            chunk.push(line.as_bytes());
+        }
+        put_chunk(&parent, &mut chunk);
+        for line in ["b", "c", "a", "c"].iter() {
+            chunk.push(line.as_bytes());
+        }
+        put_chunk(&parent, &mut chunk);
+        let parent = parent.read().unwrap();
+        assert_eq!(kept_lines(&parent), ["a", "b", "c"]);
+        assert_eq!(parent.len(), 3);
+        assert_eq!(parent.num_duplicates(), 4);
+        assert_eq!(parent.num_removed(), 0);
+    }
+
+    #[test]
+    fn last_duplicates_replace_lines_of_earlier_chunks() {
+        let (parent, mut chunk) = parent_and_chunk(&["--unique=last"]);
+        chunk.add_source(0, None, None);
+        for line in ["a", "b", "a"].iter() {
+            chunk.push(line.as_bytes());
+        }
+        assert!(chunk.is_removed(0));
+        put_chunk(&parent, &mut chunk);
+        // `b` at 1 and `a` at 2 are replaced by lines at 4 and 5.
+        for line in ["c", "b", "a"].iter() {
+            chunk.push(line.as_bytes());
+        }
+        put_chunk(&parent, &mut chunk);
+        let parent = parent.read().unwrap();
+        assert_eq!(parent.len(), 6);
+        assert_eq!(
+            (0..6).map(|i| parent.is_removed(i)).collect::<Vec<_>>(),
+            [true, true, true, false, false, false]
+        );
+        assert_eq!(kept_lines(&parent), ["c", "b", "a"]);
+        assert_eq!(parent.num_duplicates(), 3);
+        assert_eq!(parent.num_removed(), 3);
+    }
+
+    #[test]
+    fn mapped_duplicates_are_removed() {
+        let (parent, mut chunk) = parent_and_chunk(&["--unique"]);
+        chunk.add_source(0, None, None);
+        chunk.push(b"b");
+        put_chunk(&parent, &mut chunk);
+        chunk.add_source(1, None, Some(mapped("unique", b"a\nb\na\nc")));
+        chunk.index_mapped_lines(2);
+        put_chunk(&parent, &mut chunk);
+        chunk.index_mapped_lines(2);
+        put_chunk(&parent, &mut chunk);
+        let parent = parent.read().unwrap();
+        assert_eq!(parent.len(), 5);
+        assert_eq!(kept_lines(&parent), ["b", "a", "c"]);
+        assert_eq!(parent.num_duplicates(), 2);
+        assert_eq!(parent.num_removed(), 2);
+    }
+
+    #[test]
+    fn reset_chunk_forgets_lines() {
+        let (parent, mut chunk) = parent_and_chunk(&["--unique"]);
+        chunk.add_source(0, None, None);
+        chunk.push(b"a");
+        put_chunk(&parent, &mut chunk);
+        chunk.reset();
+        parent.write().unwrap().reset();
+        chunk.push(b"a");
+        chunk.push(b"a");
+        put_chunk(&parent, &mut chunk);
+        assert_eq!(kept_lines(&parent.read().unwrap()), ["a"]);
     }
 
-    pub fn put_chunk(&mut self, chunk: Vec<Arc<Line>>) {
-        self.lines.extend(chunk);
+    #[test]
+    fn only_chunks_tell_duplicates() {
+        let mut storage = line_storage(&["--unique"]);
+        storage.add_source(0, None, None);
+        storage.push(b"a");
+        storage.push(b"a");
+        assert_eq!(storage.len(), 2);
     }
 }
@@ -1,28 +1,40 @@
 extern crate clap;
+extern crate encoding_rs;
 extern crate libc;
+extern crate memchr;
 extern crate ncurses;
+extern crate regex;
+extern crate unicode_normalization;
 extern crate unicode_width;
 
+mod ansi;
 mod app;
 mod commander;
 mod config;
 mod coordinator;
-mod item;
+mod encoding;
+mod field;
+mod index_set;
 mod item_list;
 mod key;
 mod libc_aux;
 mod line;
 mod line_storage;
 mod match_info_cache;
+mod mmap;
+mod normalizer;
 mod pattern;
 mod query;
 mod reader;
+mod romaji;
 mod screen;
 mod screen_data;
 mod search;
+mod search_text;
 mod searcher;
 mod state;
 mod thread_util;
+mod unique;
 mod window;
 
 fn main() {
@@ -1,26 +1,236 @@
+use std::cell::{Cell, RefCell};
 use std::collections::BTreeMap;
+use std::mem;
 
 use crate::search::MatchInfo;
 
+/// Caches match info per query string. When the cached entries take more
+/// than `capacity` bytes, the least recently used ones are evicted.
 pub struct MatchInfoCache {
-    cache: BTreeMap<String, MatchInfo>,
+    cache: BTreeMap<String, Entry>,
+    capacity: usize,
+    clock: Cell<u64>,
+    // Query strings of the entries by when they were last used, so that the
+    // least recently used one comes first.
+    recency: RefCell<BTreeMap<u64, String>>,
+    size: usize,
+    stats: Cell<CacheStats>,
+}
+
+struct Entry {
+    last_used: Cell<u64>,
+    match_info: MatchInfo,
+    size: usize,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct CacheStats {
+    pub evictions: usize,
+    pub hits: usize,
+    pub misses: usize,
+    pub size: usize,
 }
 
 impl MatchInfoCache {
-    pub fn new() -> Self {
+    pub fn new(capacity: usize) -> Self {
         MatchInfoCache {
             cache: BTreeMap::new(),
+            capacity,
+            clock: Cell::new(0),
+            recency: RefCell::new(BTreeMap::new()),
+            size: 0,
+            stats: Cell::new(CacheStats::default()),
         }
     }
 
+    pub fn clear(&mut self) {
+        self.cache.clear();
+        self.recency.get_mut().clear();
+        self.size = 0;
+    }
+
+    /// Looks up the match info for `query_string`, counting a hit or a miss.
     pub fn get(&self, query_string: &str) -> Option<&MatchInfo> {
-        self.cache.get(query_string)
+        let mut stats = self.stats.get();
+        let entry = self.cache.get(query_string);
+        match entry {
+            Some(entry) => {
+                self.touch(entry);
+                stats.hits += 1;
+            }
+            None => stats.misses += 1,
+        }
+        self.stats.set(stats);
+        entry.map(|entry| &entry.match_info)
+    }
+
+    /// Like `get` but neither counts a hit or a miss nor makes the entry
+    /// recently used.
+    pub fn peek(&self, query_string: &str) -> Option<&MatchInfo> {
+        self.cache.get(query_string).map(|entry| &entry.match_info)
+    }
+
+    /// Merges `info` into the entry for `query_string` and returns true,
+    /// or returns false if `info` starts after the lines the entry covers,
+    /// e.g. as the entry was evicted while the rest of the lines were
+    /// searched. The entry is kept even if it alone exceeds the capacity, as
+    /// it is the one being shown.
+    pub fn insert(&mut self, query_string: String, info: MatchInfo) -> bool {
+        let end = self
+            .cache
+            .get(&query_string)
+            .map_or(0, |entry| entry.match_info.index_range.end);
+        if info.index_range.start > end {
+            return false;
+        }
+        // The key is stored twice, in the cache and in the recency order.
+        let key_size = 2 * query_string.capacity();
+        let entry = match self.cache.get(&query_string) {
+            Some(entry) => {
+                self.touch(entry);
+                self.cache.get_mut(&query_string).unwrap()
+            }
+            None => {
+                let last_used = self.tick();
+                self.recency
+                    .get_mut()
+                    .insert(last_used, query_string.clone());
+                self.cache.entry(query_string).or_insert(Entry {
+                    last_used: Cell::new(last_used),
+                    match_info: MatchInfo::default(),
+                    size: 0,
+                })
+            }
+        };
+        entry.match_info.merge(info);
+        entry.match_info.compact();
+        let size = mem::size_of::<Entry>() + key_size + entry.match_info.heap_size();
+        self.size = self.size - entry.size + size;
+        entry.size = size;
+        self.evict();
+        true
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            size: self.size,
+            ..self.stats.get()
+        }
+    }
+
+    // Evicts least recently used entries until the cache fits in its
+    // capacity, but not the most recently used one.
+    fn evict(&mut self) {
+        while self.size > self.capacity && self.cache.len() > 1 {
+            let (_, query_string) = self.recency.get_mut().pop_first().unwrap();
+            let entry = self.cache.remove(&query_string).unwrap();
+            self.size -= entry.size;
+            let mut stats = self.stats.get();
+            stats.evictions += 1;
+            self.stats.set(stats);
+        }
+    }
+
+    // Makes `entry` the most recently used one.
+    fn touch(&self, entry: &Entry) {
+        let time = self.tick();
+        let mut recency = self.recency.borrow_mut();
+        let query_string = recency.remove(&entry.last_used.get()).unwrap();
+        recency.insert(time, query_string);
+        entry.last_used.set(time);
+    }
+
+    fn tick(&self) -> u64 {
+        let time = self.clock.get() + 1;
+        self.clock.set(time);
+        time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn match_info(line_indices: Vec<usize>, end: usize) -> MatchInfo {
+        MatchInfo {
+            line_indices: line_indices.into(),
+            points: Vec::new(),
+            index_range: 0..end,
+        }
+    }
+
+    fn entry_size(query_string: &str, info: MatchInfo) -> usize {
+        let mut cache = MatchInfoCache::new(usize::MAX);
+        cache.insert(query_string.to_owned(), info);
+        cache.stats().size
+    }
+
+    #[test]
+    fn least_recently_used_entry_is_evicted() {
+        let size = entry_size("a", match_info(vec![1, 5, 9], 10));
+        let mut cache = MatchInfoCache::new(size * 3);
+        cache.insert("a".to_owned(), match_info(vec![1, 5, 9], 10));
+        cache.insert("b".to_owned(), match_info(vec![2, 5, 8], 10));
+        cache.insert("c".to_owned(), match_info(vec![3, 5, 7], 10));
+        assert!(cache.get("a").is_some());
+        cache.insert("d".to_owned(), match_info(vec![4, 5, 6], 10));
+        assert!(cache.peek("a").is_some());
+        assert!(cache.peek("b").is_none());
+        assert!(cache.peek("c").is_some());
+        assert!(cache.peek("d").is_some());
+        assert_eq!(cache.stats().evictions, 1);
+        assert_eq!(cache.stats().size, size * 3);
+    }
+
+    #[test]
+    fn peek_does_not_make_entry_recently_used() {
+        let size = entry_size("a", match_info(vec![1, 5, 9], 10));
+        let mut cache = MatchInfoCache::new(size * 2);
+        cache.insert("a".to_owned(), match_info(vec![1, 5, 9], 10));
+        cache.insert("b".to_owned(), match_info(vec![2, 5, 8], 10));
+        assert!(cache.peek("a").is_some());
+        cache.insert("c".to_owned(), match_info(vec![3, 5, 7], 10));
+        assert!(cache.peek("a").is_none());
+        assert!(cache.peek("b").is_some());
+    }
+
+    #[test]
+    fn entry_larger_than_capacity_is_kept() {
+        let mut cache = MatchInfoCache::new(1);
+        cache.insert("a".to_owned(), match_info(vec![1, 5, 9], 10));
+        cache.insert("b".to_owned(), match_info(vec![2, 5, 8], 10));
+        assert!(cache.peek("a").is_none());
+        assert!(cache.peek("b").is_some());
+        assert_eq!(cache.stats().evictions, 1);
+    }
+
+    #[test]
+    fn inserting_merges_into_entry() {
+        let mut cache = MatchInfoCache::new(usize::MAX);
+        cache.insert("a".to_owned(), match_info(vec![1, 5], 10));
+        let mut rest = match_info(vec![5, 12], 20);
+        rest.index_range.start = 5;
+        cache.insert("a".to_owned(), rest);
+        let info = cache.get("a").unwrap();
+        assert_eq!(info.line_indices.iter().collect::<Vec<_>>(), vec![1, 5, 12]);
+        assert_eq!(info.index_range, 0..20);
+        assert_eq!(cache.stats().hits, 1);
     }
 
-    pub fn insert(&mut self, query_string: String, info: MatchInfo) {
-        self.cache
-            .entry(query_string)
-            .or_insert_with(Default::default)
-            .merge(info);
+    #[test]
+    fn rest_of_evicted_entry_is_dropped() {
+        let size = entry_size("a", match_info(vec![1, 5, 9], 10));
+        let mut cache = MatchInfoCache::new(size);
+        cache.insert("a".to_owned(), match_info(vec![1, 5, 9], 10));
+        cache.insert("b".to_owned(), match_info(vec![2, 5, 8], 10));
+        assert!(cache.peek("a").is_none());
+        let mut rest = match_info(vec![12], 20);
+        rest.index_range.start = 10;
+        assert!(!cache.insert("a".to_owned(), rest));
+        assert!(cache.peek("a").is_none());
+        let mut rest = match_info(vec![12], 20);
+        rest.index_range.start = 10;
+        assert!(cache.insert("b".to_owned(), rest));
+        assert_eq!(cache.peek("b").unwrap().index_range, 0..20);
     }
 }
@@ -0,0 +1,82 @@
+use std::fs::File;
+use std::ops::Deref;
+use std::os::unix::io::AsRawFd;
+use std::{ptr, slice};
+
+/// A read-only memory map of a whole file. The file must not be truncated
+/// while it is mapped or reading the missing part kills the process, so
+/// files that may be written to are not mapped.
+pub struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is read-only and never moves, so sharing it is safe.
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Maps the file at `path`, or returns `None` if it is not a non-empty
+    /// regular file, is open for writing or cannot be mapped.
+    pub fn open(path: &str) -> Option<Self> {
+        let file = File::open(path).ok()?;
+        let metadata = file.metadata().ok()?;
+        if !metadata.is_file() || metadata.len() == 0 || is_open_for_writing(&file) {
+            return None;
+        }
+        let len = metadata.len() as usize;
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+        Some(Mmap { ptr, len })
+    }
+}
+
+// Returns true if a process may be writing to `file`, e.g. a logger whose log
+// can be rotated by truncating it. A read lease can only be taken on a file
+// no one has open for writing; failing to take one for another reason, such
+// as not owning the file, counts as open for writing too.
+#[cfg(target_os = "linux")]
+fn is_open_for_writing(file: &File) -> bool {
+    let fd = file.as_raw_fd();
+    unsafe {
+        if libc::fcntl(fd, libc::F_SETLEASE, libc::F_RDLCK) == -1 {
+            return true;
+        }
+        libc::fcntl(fd, libc::F_SETLEASE, libc::F_UNLCK);
+    }
+    false
+}
+
+// Other systems have no way to tell; `--no-mmap` is there for files that are
+// written to.
+#[cfg(not(target_os = "linux"))]
+fn is_open_for_writing(_file: &File) -> bool {
+    false
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use std::iter;
+use unicode_normalization::char::{decompose_canonical, is_combining_mark};
+
+use crate::config::Config;
+
+/// Folds chars that should compare equal into a canonical form. It is
+/// applied to both lines and queries before matching.
+#[derive(Clone, Copy)]
+pub struct Normalizer {
+    folds_kana: bool,
+    strips_diacritics: bool,
+}
+
+impl Normalizer {
+    pub fn new(config: &Config) -> Self {
+        Normalizer {
+            folds_kana: config.folds_kana(),
+            strips_diacritics: config.strips_diacritics(),
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        !self.folds_kana && !self.strips_diacritics
+    }
+
+    /// Appends the normalized `s` to `normalized`. For each byte appended,
+    /// `origins` receives `base` plus the offset in `s` of the char it came
+    /// from.
+    pub fn normalize_into(
+        &self,
+        s: &str,
+        base: usize,
+        normalized: &mut String,
+        origins: &mut Vec<usize>,
+    ) {
+        for (i, ch) in s.char_indices() {
+            if self.strips_diacritics {
+                decompose_canonical(ch, |ch| {
+                    if !is_diacritic(ch) {
+                        self.push(ch, base + i, normalized, origins);
+                    }
+                });
+            } else {
+                self.push(ch, base + i, normalized, origins);
+            }
+        }
+    }
+
+    fn push(&self, ch: char, origin: usize, normalized: &mut String, origins: &mut Vec<usize>) {
+        let ch = if self.folds_kana {
+            if let Some(voiced) = normalized
+                .chars()
+                .next_back()
+                .and_then(|last| voice_kana(last, ch))
+            {
+                // The composed char takes over the origin of its base.
+                let base_len = normalized.pop().unwrap().len_utf8();
+                let base_origin = origins[origins.len() - base_len];
+                origins.truncate(origins.len() - base_len);
+                normalized.push(voiced);
+                origins.extend(iter::repeat_n(base_origin, voiced.len_utf8()));
+                return;
+            }
+            fold_kana(ch)
+        } else {
+            ch
+        };
+        normalized.push(ch);
+        origins.extend(iter::repeat_n(origin, ch.len_utf8()));
+    }
+
+    pub fn normalize_str(&self, s: &str) -> String {
+        if self.is_identity() {
+            return s.to_owned();
+        }
+        let mut normalized = String::with_capacity(s.len());
+        self.normalize_into(s, 0, &mut normalized, &mut Vec::new());
+        normalized
+    }
+}
+
+// Kana voiced sound marks are combining marks too, but `が` and `か` are
+// different letters rather than accented variants.
+fn is_diacritic(ch: char) -> bool {
+    is_combining_mark(ch) && ch != '\u{3099}' && ch != '\u{309A}'
+}
+
+// Maps hiragana to katakana, half-width katakana to full-width and
+// full-width ASCII to ASCII.
+fn fold_kana(ch: char) -> char {
+    let u = ch as u32;
+    let folded = match u {
+        0x3041..=0x3096 | 0x309D..=0x309E => u + 0x60,
+        0x3000 => 0x20,
+        0xFF01..=0xFF5E => u - 0xFF01 + 0x21,
+        0xFF61..=0xFF9F => HALFWIDTH_KATAKANA[(u - 0xFF61) as usize] as u32,
+        _ => u,
+    };
+    std::char::from_u32(folded).unwrap_or(ch)
+}
+
+// Composes a katakana with a following (semi-)voiced sound mark, e.g.
+// `ｶﾞ` -> `ガ`, which is needed to match a half-width form against a
+// full-width one.
+fn voice_kana(base: char, mark: char) -> Option<char> {
+    let is_voiced = match mark {
+        '\u{3099}' | '\u{309B}' | '\u{FF9E}' => true,
+        '\u{309A}' | '\u{309C}' | '\u{FF9F}' => false,
+        _ => return None,
+    };
+    let u = base as u32;
+    let composed = match u {
+        0x30AB..=0x30C2 if u % 2 == 1 && is_voiced => u + 1,
+        0x30C4 | 0x30C6 | 0x30C8 if is_voiced => u + 1,
+        0x30CF | 0x30D2 | 0x30D5 | 0x30D8 | 0x30DB => u + if is_voiced { 1 } else { 2 },
+        0x30A6 if is_voiced => 0x30F4,
+        0x30EF..=0x30F2 if is_voiced => u + 8,
+        _ => return None,
+    };
+    std::char::from_u32(composed)
+}
+
+const HALFWIDTH_KATAKANA: [char; 63] = [
+    '。', '「', '」', '、', '・', 'ヲ', 'ァ', 'ィ', 'ゥ', 'ェ', 'ォ', 'ャ', 'ュ', 'ョ', 'ッ', 'ー',
+    'ア', 'イ', 'ウ', 'エ', 'オ', 'カ', 'キ', 'ク', 'ケ', 'コ', 'サ', 'シ', 'ス', 'セ', 'ソ', 'タ',
+    'チ', 'ツ', 'テ', 'ト', 'ナ', 'ニ', 'ヌ', 'ネ', 'ノ', 'ハ', 'ヒ', 'フ', 'ヘ', 'ホ', 'マ', 'ミ',
+    'ム', 'メ', 'モ', 'ヤ', 'ユ', 'ヨ', 'ラ', 'リ', 'ル', 'レ', 'ロ', 'ワ', 'ン', '゛', '゜',
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalizer(args: &[&str]) -> Normalizer {
+        Normalizer::new(&Config::with_test_args(args))
+    }
+
+    #[test]
+    fn fold_kana_unifies_kana_and_widths() {
+        let normalizer = normalizer(&["--fold-kana"]);
+        let table = [
+            ("かな", "カナ"),
+            ("ｶﾅ", "カナ"),
+            ("ｶﾞｷﾞﾊﾟ", "ガギパ"),
+            ("がぱ", "ガパ"),
+            ("ｳﾞｦ", "ヴヲ"),
+            ("ｰ｡", "ー。"),
+            ("ＡＢＣ１２３！", "ABC123!"),
+            ("a\u{3000}b", "a b"),
+            ("カナ abc", "カナ abc"),
+        ];
+        for &(s, normalized) in table.iter() {
+            assert_eq!(normalizer.normalize_str(s), normalized, "{}", s);
+        }
+    }
+
+    #[test]
+    fn voiced_mark_after_unvoiceable_kana_stays() {
+        let normalizer = normalizer(&["--fold-kana"]);
+        assert_eq!(normalizer.normalize_str("ｱﾞ"), "ア゛");
+    }
+
+    #[test]
+    fn origins_point_back_to_original_chars() {
+        let normalizer = normalizer(&["--fold-kana"]);
+        let mut normalized = String::new();
+        let mut origins = Vec::new();
+        normalizer.normalize_into("aｶﾞＢ", 10, &mut normalized, &mut origins);
+        assert_eq!(normalized, "aガB");
+        // `a` at 0, `ｶ` at 1 and `ﾞ` at 4 composed, `Ｂ` at 7.
+        assert_eq!(origins, vec![10, 11, 11, 11, 17]);
+    }
+
+    #[test]
+    fn identity_leaves_text_as_is() {
+        let normalizer = normalizer(&[]);
+        assert!(normalizer.is_identity());
+        assert_eq!(normalizer.normalize_str("ｶﾅ"), "ｶﾅ");
+    }
+
+    #[test]
+    fn normalize_strips_combining_marks() {
+        let normalizer = normalizer(&["--normalize"]);
+        let table = [
+            ("café", "cafe"),
+            ("cafe\u{301}", "cafe"),
+            ("Ångström", "Angstrom"),
+            ("naïve", "naive"),
+            ("ø", "ø"),
+            ("ǅ", "ǅ"),
+        ];
+        for &(s, normalized) in table.iter() {
+            assert_eq!(normalizer.normalize_str(s), normalized, "{}", s);
+        }
+    }
+
+    #[test]
+    fn normalize_keeps_kana_voiced_marks() {
+        let normalizer = normalizer(&["--normalize"]);
+        // `が` decomposes into `か` and U+3099, which is kept.
+        assert_eq!(normalizer.normalize_str("が"), "か\u{3099}");
+        let folding = self::normalizer(&["--normalize", "--fold-kana"]);
+        assert_eq!(folding.normalize_str("が"), "ガ");
+        assert_eq!(folding.normalize_str("ｶﾞé"), "ガe");
+    }
+
+    #[test]
+    fn stripped_marks_leave_no_origins() {
+        let normalizer = normalizer(&["--normalize"]);
+        let mut normalized = String::new();
+        let mut origins = Vec::new();
+        normalizer.normalize_into("e\u{301}é", 0, &mut normalized, &mut origins);
+        assert_eq!(normalized, "ee");
+        assert_eq!(origins, vec![0, 3]);
+    }
+}
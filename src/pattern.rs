@@ -1,32 +1,472 @@
+use regex::{Regex, RegexBuilder};
+use std::cmp;
+use std::ops::Range;
+
+const SCORE_MATCH: i32 = 16;
+const SCORE_GAP_START: i32 = -3;
+const SCORE_GAP_EXTENSION: i32 = -1;
+const BONUS_BOUNDARY: i32 = 8;
+const BONUS_PATH_SEPARATOR: i32 = 9;
+const BONUS_CAMEL_CASE: i32 = 7;
+const BONUS_CONSECUTIVE: i32 = 4;
+
+/// How letter case is treated when matching.
+#[derive(Clone, Copy, PartialEq)]
+pub enum CaseMatching {
+    /// Case-insensitive unless the term contains an uppercase letter.
+    Smart,
+    Ignore,
+    Respect,
+}
+
 pub struct Pattern {
     chars: Vec<char>,
+    ignores_case: bool,
+    is_inverse: bool,
+    kind: Kind,
+}
+
+enum Kind {
+    Fuzzy,  // foo
+    Exact,  // 'foo
+    Prefix, // ^foo
+    Suffix, // foo$
+    Equal,  // ^foo$
+    Regex(Regex),
 }
 
 impl Pattern {
-    pub fn test(&self, haystack: &[char]) -> bool {
-        test_fuzzy_ignorecase(haystack, &self.chars)
+    pub fn new(token: &str, case_matching: CaseMatching) -> Option<Pattern> {
+        let mut s = token;
+        let mut is_inverse = false;
+        let mut kind = Kind::Fuzzy;
+        if s.starts_with('!') {
+            s = &s[1..];
+            is_inverse = true;
+            kind = Kind::Exact;
+        }
+        if s.starts_with('\'') {
+            s = &s[1..];
+            kind = Kind::Exact;
+        } else {
+            let has_prefix = s.starts_with('^');
+            if has_prefix {
+                s = &s[1..];
+            }
+            let has_suffix = s.len() > 1 && s.ends_with('$');
+            if has_suffix {
+                s = &s[..s.len() - 1];
+            }
+            kind = match (has_prefix, has_suffix) {
+                (true, true) => Kind::Equal,
+                (true, false) => Kind::Prefix,
+                (false, true) => Kind::Suffix,
+                (false, false) => kind,
+            };
+        }
+        if s.is_empty() {
+            return None;
+        }
+        let ignores_case = match case_matching {
+            CaseMatching::Smart => !s.chars().any(char::is_uppercase),
+            CaseMatching::Ignore => true,
+            CaseMatching::Respect => false,
+        };
+        let mut pattern = Pattern {
+            chars: Vec::new(),
+            ignores_case,
+            is_inverse,
+            kind,
+        };
+        pattern.chars = s.chars().map(|ch| pattern.fold(ch)).collect();
+        Some(pattern)
     }
-}
 
-pub fn patterns_from_str(s: &str) -> Vec<Pattern> {
-    s.split_whitespace()
-        .map(|t| Pattern {
-            chars: t.chars().collect(),
-        })
-        .collect()
+    /// Compiles `token` as a regular expression. A leading `!` still means
+    /// inverse match; the other modifiers are left to the regex syntax.
+    pub fn new_regex(
+        token: &str,
+        case_matching: CaseMatching,
+    ) -> Result<Option<Pattern>, regex::Error> {
+        let (s, is_inverse) = match token.strip_prefix('!') {
+            Some(s) => (s, true),
+            None => (token, false),
+        };
+        if s.is_empty() {
+            return Ok(None);
+        }
+        let ignores_case = match case_matching {
+            CaseMatching::Smart => !has_uppercase_literal(s),
+            CaseMatching::Ignore => true,
+            CaseMatching::Respect => false,
+        };
+        let regex = RegexBuilder::new(s)
+            .case_insensitive(ignores_case)
+            .build()?;
+        Ok(Some(Pattern {
+            chars: Vec::new(),
+            ignores_case,
+            is_inverse,
+            kind: Kind::Regex(regex),
+        }))
+    }
+
+    pub fn test(&self, haystack: &str) -> bool {
+        let found = match self.kind {
+            Kind::Fuzzy => self.test_fuzzy(haystack),
+            Kind::Regex(ref regex) => regex.is_match(haystack),
+            _ => self.find_window(haystack).is_some(),
+        };
+        found != self.is_inverse
+    }
+
+    pub fn score(&self, haystack: &str) -> Option<i32> {
+        if self.is_inverse {
+            return if self.test(haystack) { Some(0) } else { None };
+        }
+        self.positions(haystack)
+            .map(|positions| score_positions(haystack, &positions))
+    }
+
+    /// Returns the byte offsets of the matched chars in `haystack`.
+    pub fn positions(&self, haystack: &str) -> Option<Vec<usize>> {
+        if self.is_inverse {
+            return None;
+        }
+        let window = match self.kind {
+            Kind::Fuzzy => {
+                let window = self.find_fuzzy_window(haystack)?;
+                return Some(self.positions_in_window(haystack, window));
+            }
+            Kind::Regex(ref regex) => regex.find(haystack)?.range(),
+            _ => self.find_window(haystack)?,
+        };
+        let start = window.start;
+        Some(
+            haystack[window]
+                .char_indices()
+                .map(|(i, _)| start + i)
+                .collect(),
+        )
+    }
+
+    fn fold(&self, ch: char) -> char {
+        if self.ignores_case {
+            fold_case(ch)
+        } else {
+            ch
+        }
+    }
+
+    // Returns the length in bytes of the match if `haystack` starts with the
+    // pattern.
+    fn match_len_at(&self, haystack: &str) -> Option<usize> {
+        let mut char_indices = haystack.char_indices();
+        let mut len = 0;
+        for n in &self.chars {
+            let (i, h) = char_indices.next()?;
+            if self.fold(h) != *n {
+                return None;
+            }
+            len = i + h.len_utf8();
+        }
+        Some(len)
+    }
+
+    // Returns the byte range of `haystack` the pattern matched.
+    fn find_window(&self, haystack: &str) -> Option<Range<usize>> {
+        let len = haystack.len();
+        match self.kind {
+            Kind::Fuzzy => self.find_fuzzy_window(haystack),
+            Kind::Exact => haystack
+                .char_indices()
+                .find_map(|(i, _)| self.match_len_at(&haystack[i..]).map(|n| i..i + n)),
+            Kind::Prefix => self.match_len_at(haystack).map(|n| 0..n),
+            Kind::Suffix => {
+                let (start, _) = haystack.char_indices().rev().nth(self.chars.len() - 1)?;
+                match self.match_len_at(&haystack[start..]) {
+                    Some(n) if start + n == len => Some(start..len),
+                    _ => None,
+                }
+            }
+            Kind::Equal => match self.match_len_at(haystack) {
+                Some(n) if n == len => Some(0..len),
+                _ => None,
+            },
+            Kind::Regex(_) => unreachable!(),
+        }
+    }
+
+    fn test_fuzzy(&self, haystack: &str) -> bool {
+        let needle = &self.chars;
+        debug_assert!(!needle.is_empty());
+        let mut nidx = 0;
+        for ch in haystack.chars() {
+            if self.fold(ch) == needle[nidx] {
+                nidx += 1;
+                if nidx == needle.len() {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    // Finds the shortest window that ends at the first complete match.
+    // Scoring only the characters in it is cheaper than an exhaustive search
+    // and good enough to put the best hits at the top.
+    fn find_fuzzy_window(&self, haystack: &str) -> Option<Range<usize>> {
+        let needle = &self.chars;
+        debug_assert!(!needle.is_empty());
+        let mut nidx = 0;
+        let mut end = None;
+        for (i, ch) in haystack.char_indices() {
+            if self.fold(ch) == needle[nidx] {
+                nidx += 1;
+                if nidx == needle.len() {
+                    end = Some(i + ch.len_utf8());
+                    break;
+                }
+            }
+        }
+        let end = end?;
+
+        let mut nidx = needle.len();
+        for (i, ch) in haystack[..end].char_indices().rev() {
+            if self.fold(ch) == needle[nidx - 1] {
+                nidx -= 1;
+                if nidx == 0 {
+                    return Some(i..end);
+                }
+            }
+        }
+        unreachable!()
+    }
+
+    fn positions_in_window(&self, haystack: &str, window: Range<usize>) -> Vec<usize> {
+        let needle = &self.chars;
+        let start = window.start;
+        let mut positions = Vec::with_capacity(needle.len());
+        for (i, ch) in haystack[window].char_indices() {
+            if positions.len() < needle.len() && self.fold(ch) == needle[positions.len()] {
+                positions.push(start + i);
+            }
+        }
+        positions
+    }
 }
 
-fn test_fuzzy_ignorecase(haystack: &[char], needle: &[char]) -> bool {
-    debug_assert!(!needle.is_empty());
-    let mut nidx = 0;
-    for ch in haystack.iter() {
-        let ch = ch.to_ascii_lowercase();
-        if ch == needle[nidx] {
-            nidx += 1;
-            if nidx == needle.len() {
-                return true;
+/// Applies Unicode simple case folding to `ch`.
+pub fn fold_case(ch: char) -> char {
+    if ch.is_ascii() {
+        return ch.to_ascii_lowercase();
+    }
+    // Letters whose folding differs from their lowercase mapping.
+    match ch {
+        '\u{3C2}' => '\u{3C3}',   // ς -> σ
+        '\u{17F}' => 's',         // ſ
+        '\u{1E9B}' => '\u{1E61}', // ẛ -> ṡ
+        '\u{1FBE}' => '\u{3B9}',  // ι -> ι
+        '\u{3D0}' => '\u{3B2}',   // ϐ -> β
+        '\u{3D1}' => '\u{3B8}',   // ϑ -> θ
+        '\u{3D5}' => '\u{3C6}',   // ϕ -> φ
+        '\u{3D6}' => '\u{3C0}',   // ϖ -> π
+        '\u{3F0}' => '\u{3BA}',   // ϰ -> κ
+        '\u{3F1}' => '\u{3C1}',   // ϱ -> ρ
+        '\u{3F5}' => '\u{3B5}',   // ϵ -> ε
+        _ => {
+            let mut lower = ch.to_lowercase();
+            match (lower.next(), lower.next()) {
+                (Some(folded), None) => folded,
+                _ => ch,
             }
         }
     }
+}
+
+// Like `str::chars().any(char::is_uppercase)` but skips escaped chars so that
+// `\W` or `\S` does not make a regex case-sensitive.
+fn has_uppercase_literal(s: &str) -> bool {
+    let mut chars = s.chars();
+    while let Some(ch) = chars.next() {
+        if ch == '\\' {
+            chars.next();
+        } else if ch.is_uppercase() {
+            return true;
+        }
+    }
     false
 }
+
+// Consecutive matches inherit the bonus of the first char of their chunk so
+// that `ab` scores `ab` higher than `a_b`.
+fn score_positions(haystack: &str, positions: &[usize]) -> i32 {
+    let mut score = 0;
+    let mut prev: Option<usize> = None;
+    let mut chunk_bonus = 0;
+    for &i in positions {
+        let bonus = boundary_bonus(haystack, i);
+        score += SCORE_MATCH;
+        match prev.map(|prev| haystack[prev..i].chars().count() - 1) {
+            Some(0) => {
+                chunk_bonus = cmp::max(chunk_bonus, cmp::max(bonus, BONUS_CONSECUTIVE));
+                score += chunk_bonus;
+            }
+            Some(gap) => {
+                score += bonus + SCORE_GAP_START + (gap as i32 - 1) * SCORE_GAP_EXTENSION;
+                chunk_bonus = bonus;
+            }
+            None => {
+                score += bonus;
+                chunk_bonus = bonus;
+            }
+        }
+        prev = Some(i);
+    }
+    score
+}
+
+// `i` is the byte offset of the char to compute the bonus for.
+fn boundary_bonus(haystack: &str, i: usize) -> i32 {
+    let prev = match haystack[..i].chars().next_back() {
+        Some(prev) => prev,
+        None => return BONUS_BOUNDARY,
+    };
+    let ch = haystack[i..].chars().next().unwrap();
+    match prev {
+        '/' | '\\' => BONUS_PATH_SEPARATOR,
+        '_' | '-' | '.' | ':' | ',' | ';' | '(' | '[' | '{' | '"' | '\'' => BONUS_BOUNDARY,
+        _ if prev.is_whitespace() => BONUS_BOUNDARY,
+        _ if prev.is_lowercase() && ch.is_uppercase() => BONUS_CAMEL_CASE,
+        _ if !prev.is_numeric() && ch.is_numeric() => BONUS_CAMEL_CASE,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(token: &str, haystack: &str) -> bool {
+        Pattern::new(token, CaseMatching::Smart)
+            .unwrap()
+            .test(haystack)
+    }
+
+    #[test]
+    fn fuzzy_matches_chars_in_order() {
+        assert!(matches("fbr", "foo bar"));
+        assert!(!matches("bf", "foo bar"));
+    }
+
+    #[test]
+    fn quote_matches_exactly() {
+        assert!(matches("'oba", "foobar"));
+        assert!(!matches("'fbr", "foobar"));
+    }
+
+    #[test]
+    fn caret_matches_prefix() {
+        assert!(matches("^foo", "foobar"));
+        assert!(!matches("^foo", "barfoo"));
+    }
+
+    #[test]
+    fn dollar_matches_suffix() {
+        assert!(matches("bar$", "foobar"));
+        assert!(!matches("bar$", "barfoo"));
+    }
+
+    #[test]
+    fn caret_and_dollar_match_whole_line() {
+        assert!(matches("^foo$", "foo"));
+        assert!(!matches("^foo$", "foobar"));
+    }
+
+    #[test]
+    fn bang_inverts_exact_match() {
+        assert!(!matches("!foo", "foobar"));
+        assert!(matches("!foo", "bar"));
+        // Not fuzzy: `f` and `r` are apart.
+        assert!(matches("!fr", "foobar"));
+    }
+
+    #[test]
+    fn bang_combines_with_anchors() {
+        assert!(!matches("!^foo", "foobar"));
+        assert!(matches("!^foo", "barfoo"));
+        assert!(!matches("!bar$", "foobar"));
+        assert!(matches("!bar$", "barfoo"));
+    }
+
+    #[test]
+    fn modifiers_alone_are_no_pattern() {
+        for token in ["!", "'", "^", "!'", "!^"].iter() {
+            assert!(
+                Pattern::new(token, CaseMatching::Smart).is_none(),
+                "{}",
+                token
+            );
+        }
+    }
+
+    #[test]
+    fn lone_dollar_is_literal() {
+        assert!(matches("$", "$HOME"));
+        assert!(!matches("$", "HOME"));
+    }
+
+    #[test]
+    fn fold_case_follows_simple_case_folding() {
+        let table = [
+            ('A', 'a'),
+            ('a', 'a'),
+            ('Ä', 'ä'),
+            ('Σ', 'σ'),
+            ('σ', 'σ'),
+            ('ς', 'σ'),
+            ('ẞ', 'ß'),
+            ('ß', 'ß'),
+            ('ſ', 's'),
+            ('ϐ', 'β'),
+            ('Ａ', 'ａ'),
+            ('İ', 'İ'),
+            ('1', '1'),
+            ('カ', 'カ'),
+        ];
+        for &(ch, folded) in table.iter() {
+            assert_eq!(fold_case(ch), folded, "{}", ch);
+        }
+    }
+
+    #[test]
+    fn lowercase_query_ignores_case() {
+        assert!(matches("σοφια", "ΣΟΦΙΑ"));
+        assert!(matches("'όσος", "ΌΣΟΣ"));
+        assert!(matches("'straße", "STRAẞE"));
+        assert!(matches("ä", "Ärger"));
+    }
+
+    #[test]
+    fn uppercase_query_respects_case() {
+        assert!(matches("Ä", "Ärger"));
+        assert!(!matches("Ä", "ärger"));
+        assert!(!matches("Σ", "σ"));
+    }
+
+    #[test]
+    fn case_matching_can_be_forced() {
+        let ignoring = Pattern::new("Ä", CaseMatching::Ignore).unwrap();
+        assert!(ignoring.test("ärger"));
+        let respecting = Pattern::new("ä", CaseMatching::Respect).unwrap();
+        assert!(!respecting.test("Ärger"));
+    }
+
+    #[test]
+    fn positions_are_byte_offsets_of_matched_chars() {
+        let pattern = Pattern::new("σς", CaseMatching::Smart).unwrap();
+        assert_eq!(pattern.positions("αΣβΣ"), Some(vec![2, 6]));
+        let pattern = Pattern::new("'ss", CaseMatching::Smart).unwrap();
+        assert_eq!(pattern.positions("aſs"), Some(vec![1, 3]));
+    }
+}
@@ -1,23 +1,150 @@
 use unicode_width::UnicodeWidthStr;
 
+use crate::config::Config;
 use crate::key::Key;
-use crate::pattern::{self, Pattern};
+use crate::normalizer::Normalizer;
+use crate::pattern::{CaseMatching, Pattern};
+use crate::romaji::Romaji;
 
 pub struct Query {
-    patterns: Vec<Pattern>,
+    is_regex: bool,
+    term: Term,
     string: String,
 }
 
 impl Query {
-    fn new(string: String) -> Self {
-        Query {
-            patterns: pattern::patterns_from_str(&string),
+    fn new(
+        string: String,
+        is_regex: bool,
+        case_matching: CaseMatching,
+        normalizer: &Normalizer,
+        romaji: Option<&Romaji>,
+    ) -> Result<Self, String> {
+        let normalized = normalizer.normalize_str(&string);
+        let term = Term::parse(&normalized, is_regex, case_matching, romaji).map_err(|e| {
+            // The detailed message spans several lines; the last one is the
+            // summary that fits in the status line.
+            let message = e.to_string();
+            message.lines().last().unwrap_or("").trim().to_owned()
+        })?;
+        Ok(Query {
+            is_regex,
+            term,
             string,
+        })
+    }
+
+    pub fn is_regex(&self) -> bool {
+        self.is_regex
+    }
+
+    pub fn test(&self, haystack: &str) -> bool {
+        self.term.test(haystack)
+    }
+
+    pub fn score(&self, haystack: &str) -> Option<i32> {
+        self.term.score(haystack)
+    }
+
+    /// Returns the sorted byte offsets of the chars in `haystack` consumed by
+    /// any of the patterns.
+    pub fn positions(&self, haystack: &str) -> Vec<usize> {
+        let mut positions = Vec::new();
+        self.term.collect_positions(haystack, &mut positions);
+        positions.sort_unstable();
+        positions.dedup();
+        positions
+    }
+}
+
+// A query is a conjunction of disjunctions: `foo | bar baz` is parsed as
+// `And([Or([foo, bar]), baz])`.
+enum Term {
+    And(Vec<Term>),
+    Or(Vec<Term>),
+    Pattern(Pattern),
+}
+
+impl Term {
+    fn parse(
+        s: &str,
+        is_regex: bool,
+        case_matching: CaseMatching,
+        romaji: Option<&Romaji>,
+    ) -> Result<Term, regex::Error> {
+        let mut groups: Vec<Vec<Pattern>> = Vec::new();
+        let mut continues_group = false;
+        for token in s.split_whitespace() {
+            if token == "|" {
+                continues_group = !groups.is_empty();
+                continue;
+            }
+            let patterns: Vec<Pattern> = if is_regex {
+                Pattern::new_regex(token, case_matching)?
+                    .into_iter()
+                    .collect()
+            } else if let Some(romaji) = romaji {
+                // Alternatives of a term join its OR group.
+                romaji
+                    .expand(token)
+                    .iter()
+                    .filter_map(|t| Pattern::new(t, case_matching))
+                    .collect()
+            } else {
+                Pattern::new(token, case_matching).into_iter().collect()
+            };
+            if !patterns.is_empty() {
+                match groups.last_mut() {
+                    Some(group) if continues_group => group.extend(patterns),
+                    _ => groups.push(patterns),
+                }
+                continues_group = false;
+            }
         }
+        let terms = groups
+            .into_iter()
+            .map(|mut group| {
+                if group.len() == 1 {
+                    Term::Pattern(group.pop().unwrap())
+                } else {
+                    Term::Or(group.into_iter().map(Term::Pattern).collect())
+                }
+            })
+            .collect();
+        Ok(Term::And(terms))
     }
 
-    pub fn test(&self, haystack: &[char]) -> bool {
-        self.patterns.iter().all(|p| p.test(haystack))
+    fn test(&self, haystack: &str) -> bool {
+        match self {
+            Term::And(terms) => terms.iter().all(|t| t.test(haystack)),
+            Term::Or(terms) => terms.iter().any(|t| t.test(haystack)),
+            Term::Pattern(pattern) => pattern.test(haystack),
+        }
+    }
+
+    fn score(&self, haystack: &str) -> Option<i32> {
+        match self {
+            Term::And(terms) => terms
+                .iter()
+                .try_fold(0, |acc, t| t.score(haystack).map(|s| acc + s)),
+            Term::Or(terms) => terms.iter().filter_map(|t| t.score(haystack)).max(),
+            Term::Pattern(pattern) => pattern.score(haystack),
+        }
+    }
+
+    fn collect_positions(&self, haystack: &str, positions: &mut Vec<usize>) {
+        match self {
+            Term::And(terms) | Term::Or(terms) => {
+                for term in terms {
+                    term.collect_positions(haystack, positions);
+                }
+            }
+            Term::Pattern(pattern) => {
+                if let Some(ps) = pattern.positions(haystack) {
+                    positions.extend(ps);
+                }
+            }
+        }
     }
 }
 
@@ -28,20 +155,26 @@ impl AsRef<str> for Query {
 }
 
 pub struct QueryEditor {
+    case_matching: CaseMatching,
+    // The number of chars before the cursor.
     cursor_position: usize,
+    is_cjk: bool,
+    is_regex: bool,
+    normalizer: Normalizer,
+    romaji: Option<Romaji>,
     string: String,
 }
 
 impl QueryEditor {
-    pub fn new<S: Into<String>>(string: S, is_cjk: bool) -> QueryEditor {
-        let string = string.into();
-        let width = if is_cjk {
-            <str as UnicodeWidthStr>::width_cjk(&string)
-        } else {
-            <str as UnicodeWidthStr>::width(&string)
-        };
+    pub fn new(config: &Config) -> QueryEditor {
+        let string = config.initial_query().unwrap_or("").to_owned();
         QueryEditor {
-            cursor_position: width,
+            case_matching: config.case_matching(),
+            cursor_position: string.chars().count(),
+            is_cjk: config.is_cjk(),
+            is_regex: config.is_regex(),
+            normalizer: Normalizer::new(config),
+            romaji: Romaji::new(config),
             string,
         }
     }
@@ -52,48 +185,40 @@ impl QueryEditor {
             CtrlA => {
                 self.cursor_position = 0;
             }
-            CtrlB => {
-                if self.cursor_position > 0 {
-                    self.cursor_position -= 1;
-                }
+            CtrlB if self.cursor_position > 0 => {
+                self.cursor_position -= 1;
             }
-            CtrlD => {
-                if self.cursor_position < self.string.len() {
-                    let cursor = self.cursor_position;
-                    self.string.remove(cursor);
-                }
+            CtrlD if self.cursor_position < self.num_chars() => {
+                let cursor = self.byte_offset(self.cursor_position);
+                self.string.remove(cursor);
             }
             CtrlE => {
-                self.cursor_position = self.string.len();
+                self.cursor_position = self.num_chars();
             }
-            CtrlF => {
-                if self.cursor_position < self.string.len() {
-                    self.cursor_position += 1;
-                }
+            CtrlF if self.cursor_position < self.num_chars() => {
+                self.cursor_position += 1;
             }
-            CtrlH | Del => {
-                if self.cursor_position > 0 {
-                    let cursor = self.cursor_position;
-                    self.string.remove(cursor - 1);
-                    self.cursor_position -= 1;
-                }
+            CtrlH | Del if self.cursor_position > 0 => {
+                let cursor = self.byte_offset(self.cursor_position - 1);
+                self.string.remove(cursor);
+                self.cursor_position -= 1;
             }
             CtrlK => {
-                let cursor = self.cursor_position;
+                let cursor = self.byte_offset(self.cursor_position);
                 self.string.truncate(cursor);
             }
             CtrlW => {
-                let cursor = self.cursor_position;
+                let cursor = self.byte_offset(self.cursor_position);
                 let word_end = self.string[0..cursor].rfind(|ch| ch != ' ').unwrap_or(0);
                 let word_start = self.string[0..word_end]
                     .rfind(' ')
                     .map(|i| i + 1)
                     .unwrap_or(0);
                 self.string.truncate(word_start);
-                self.cursor_position = word_start;
+                self.cursor_position = self.num_chars();
             }
             Char(ch) => {
-                let cursor = self.cursor_position;
+                let cursor = self.byte_offset(self.cursor_position);
                 self.string.insert(cursor, ch);
                 self.cursor_position += 1;
             }
@@ -101,12 +226,58 @@ impl QueryEditor {
         }
     }
 
-    pub fn cursor_position(&self) -> usize {
-        self.cursor_position as usize
+    /// Returns the column of the cursor in the query as displayed.
+    pub fn cursor_column(&self) -> usize {
+        let s = &self.string[..self.byte_offset(self.cursor_position)];
+        if self.is_cjk {
+            <str as UnicodeWidthStr>::width_cjk(s)
+        } else {
+            <str as UnicodeWidthStr>::width(s)
+        }
+    }
+
+    pub fn is_regex(&self) -> bool {
+        self.is_regex
+    }
+
+    pub fn toggle_regex(&mut self) {
+        self.is_regex = !self.is_regex;
+    }
+
+    /// Returns true if every line matching the current query also matches
+    /// `broader`, which holds when the query only extends it. Regexes,
+    /// romaji alternatives and `!`, `|` or `$` make that unreliable.
+    pub fn narrows(&self, broader: &str) -> bool {
+        if self.is_regex || self.romaji.is_some() || broader.ends_with('$') {
+            return false;
+        }
+        let broader = self.normalizer.normalize_str(broader);
+        let string = self.normalizer.normalize_str(&self.string);
+        string.starts_with(&broader) && !string.contains(['!', '|'])
     }
 
-    pub fn query(&self) -> Query {
-        Query::new(self.string.clone())
+    // Returns the byte offset of the char at `char_index`, or the length of
+    // the string if there are not as many chars.
+    fn byte_offset(&self, char_index: usize) -> usize {
+        self.string
+            .char_indices()
+            .nth(char_index)
+            .map(|(i, _)| i)
+            .unwrap_or(self.string.len())
+    }
+
+    fn num_chars(&self) -> usize {
+        self.string.chars().count()
+    }
+
+    pub fn query(&self) -> Result<Query, String> {
+        Query::new(
+            self.string.clone(),
+            self.is_regex,
+            self.case_matching,
+            &self.normalizer,
+            self.romaji.as_ref(),
+        )
     }
 }
 
@@ -115,3 +286,160 @@ impl AsRef<str> for QueryEditor {
         self.string.as_ref()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Describes the tree of `query`, e.g. `(a|b) c` for `And([Or([a, b]), c])`.
+    fn shape(query: &str) -> String {
+        fn describe(term: &Term, pattern_names: &mut impl Iterator<Item = String>) -> String {
+            match term {
+                Term::And(terms) => terms
+                    .iter()
+                    .map(|t| describe(t, pattern_names))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                Term::Or(terms) => {
+                    let terms: Vec<_> = terms.iter().map(|t| describe(t, pattern_names)).collect();
+                    format!("({})", terms.join("|"))
+                }
+                Term::Pattern(_) => pattern_names.next().unwrap(),
+            }
+        }
+        let term = Term::parse(query, false, CaseMatching::Smart, None).unwrap();
+        let mut pattern_names = query
+            .split_whitespace()
+            .filter(|token| *token != "|" && Pattern::new(token, CaseMatching::Smart).is_some())
+            .map(|token| token.to_owned());
+        describe(&term, &mut pattern_names)
+    }
+
+    fn test(query: &str, haystack: &str) -> bool {
+        Term::parse(query, false, CaseMatching::Smart, None)
+            .unwrap()
+            .test(haystack)
+    }
+
+    #[test]
+    fn spaces_separate_and_terms() {
+        assert_eq!(shape("foo bar baz"), "foo bar baz");
+        assert!(test("foo bar", "bar foo"));
+        assert!(!test("foo bar", "foo"));
+    }
+
+    #[test]
+    fn bar_groups_adjacent_terms() {
+        assert_eq!(shape("foo | bar baz"), "(foo|bar) baz");
+        assert_eq!(shape("foo bar | baz | qux"), "foo (bar|baz|qux)");
+        assert!(test("foo | bar baz", "bar baz"));
+        assert!(!test("foo | bar baz", "foo bar"));
+    }
+
+    #[test]
+    fn stray_bars_are_ignored() {
+        assert_eq!(shape("| foo"), "foo");
+        assert_eq!(shape("foo |"), "foo");
+        assert_eq!(shape("foo | | bar"), "(foo|bar)");
+    }
+
+    #[test]
+    fn empty_patterns_leave_no_group() {
+        assert_eq!(shape("foo | ! bar"), "(foo|bar)");
+        assert_eq!(shape("!"), "");
+        assert!(test("", "anything"));
+    }
+
+    #[test]
+    fn negation_applies_within_group() {
+        assert!(test("!foo | bar", "bar"));
+        assert!(test("!foo | bar", "baz"));
+        assert!(!test("!foo | bar", "foo"));
+    }
+
+    fn editor(args: &[&str], query: &str) -> QueryEditor {
+        let mut editor = QueryEditor::new(&Config::with_test_args(args));
+        for ch in query.chars() {
+            editor.put_key(Key::Char(ch));
+        }
+        editor
+    }
+
+    #[test]
+    fn extended_query_narrows() {
+        let editor = editor(&[], "foo ba");
+        assert!(editor.narrows("foo b"));
+        assert!(editor.narrows("fo"));
+        assert!(editor.narrows(""));
+        assert!(!editor.narrows("foo bar"));
+        assert!(!editor.narrows("bar"));
+    }
+
+    #[test]
+    fn anchors_and_operators_do_not_narrow() {
+        assert!(!editor(&[], "foo$x").narrows("foo$"));
+        assert!(!editor(&[], "foo !bar").narrows("foo"));
+        assert!(!editor(&[], "foo | bar").narrows("foo"));
+    }
+
+    #[test]
+    fn regex_and_romaji_queries_do_not_narrow() {
+        assert!(!editor(&["--regex"], "foo").narrows("fo"));
+        assert!(!editor(&["--romaji"], "kana").narrows("ka"));
+    }
+
+    #[test]
+    fn multibyte_chars_are_edited_by_char() {
+        let mut editor = editor(&[], "かな");
+        assert_eq!(editor.as_ref(), "かな");
+        editor.put_key(Key::CtrlB);
+        editor.put_key(Key::Char('ん'));
+        assert_eq!(editor.as_ref(), "かんな");
+        editor.put_key(Key::CtrlH);
+        assert_eq!(editor.as_ref(), "かな");
+        editor.put_key(Key::CtrlD);
+        assert_eq!(editor.as_ref(), "か");
+        editor.put_key(Key::CtrlA);
+        editor.put_key(Key::CtrlF);
+        editor.put_key(Key::Char('é'));
+        editor.put_key(Key::CtrlB);
+        editor.put_key(Key::CtrlK);
+        assert_eq!(editor.as_ref(), "か");
+    }
+
+    #[test]
+    fn cursor_column_counts_display_width() {
+        let mut editor = editor(&[], "aかé");
+        assert_eq!(editor.cursor_column(), 4);
+        editor.put_key(Key::CtrlB);
+        assert_eq!(editor.cursor_column(), 3);
+        editor.put_key(Key::CtrlB);
+        assert_eq!(editor.cursor_column(), 1);
+    }
+
+    #[test]
+    fn initial_query_puts_cursor_at_end() {
+        let mut editor = QueryEditor::new(&Config::with_test_args(&["--query", "かな"]));
+        assert_eq!(editor.cursor_column(), 4);
+        editor.put_key(Key::Char('た'));
+        assert_eq!(editor.as_ref(), "かなた");
+    }
+
+    #[test]
+    fn ctrl_w_deletes_multibyte_word() {
+        let mut editor = editor(&[], "foo かな ");
+        editor.put_key(Key::CtrlW);
+        assert_eq!(editor.as_ref(), "foo ");
+        editor.put_key(Key::Char('x'));
+        assert_eq!(editor.as_ref(), "foo x");
+    }
+
+    #[test]
+    fn hiragana_query_finds_katakana_with_fold_kana() {
+        let query = editor(&["--fold-kana"], "かな").query().unwrap();
+        let normalizer = Normalizer::new(&Config::with_test_args(&["--fold-kana"]));
+        assert!(query.test(&normalizer.normalize_str("カナ")));
+        assert!(query.test(&normalizer.normalize_str("ｶﾅ")));
+        assert!(!query.test(&normalizer.normalize_str("カタ")));
+    }
+}
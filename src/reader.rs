@@ -1,4 +1,11 @@
-use std::io::{BufRead, BufReader};
+use encoding_rs::{Encoding, UTF_16BE, UTF_8};
+use memchr::memchr;
+use std::cmp;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex, RwLock};
@@ -6,11 +13,16 @@ use std::thread;
 use std::time::Duration;
 
 use crate::config::Config;
-use crate::line::Line;
+use crate::encoding::{self, InputEncoding};
 use crate::line_storage::LineStorage;
+use crate::mmap::Mmap;
 use crate::thread_util::spawn_with_name;
 
 const DUMP_INTERVAL_MS: u64 = 20; // ~10,000 lines per dump on my laptop when piped to `find`
+const LINES_PER_INDEX: usize = 100_000;
+const FOLLOW_INTERVAL_MS: u64 = 200;
+// How much of an input its encoding is detected from.
+const SAMPLE_LEN: usize = 64 * 1024;
 
 pub enum Event {
     DidFinish,
@@ -18,39 +30,64 @@ pub enum Event {
 }
 
 pub struct Reader {
-    chunk: Arc<Mutex<Vec<Arc<Line>>>>,
+    chunk: Arc<Mutex<LineStorage>>,
     is_finished: Arc<AtomicBool>,
     line_storage: Arc<RwLock<LineStorage>>,
     reader: thread::JoinHandle<()>,
+    source_command: Option<Arc<SourceCommand>>,
 }
 
 impl Reader {
     pub fn new(config: Config, line_storage: Arc<RwLock<LineStorage>>) -> Self {
-        let chunk = Arc::new(Mutex::new(Vec::new()));
+        let chunk = Arc::new(Mutex::new(LineStorage::new_chunk(
+            &config,
+            line_storage.clone(),
+        )));
         let is_finished = Arc::new(AtomicBool::new(false));
-        let reader = spawn_parked_reader(config, chunk.clone(), is_finished.clone());
+        let source_command = config
+            .source_command()
+            .map(|command| Arc::new(SourceCommand::new(&config, command, chunk.clone())));
+        let reader = spawn_parked_reader(
+            config,
+            chunk.clone(),
+            is_finished.clone(),
+            source_command.clone(),
+        );
         Reader {
             chunk,
             is_finished,
             line_storage,
             reader,
+            source_command,
         }
     }
 
+    /// Returns the command lines are read from, which can be rerun, if any.
+    pub fn source_command(&self) -> Option<Arc<SourceCommand>> {
+        self.source_command.clone()
+    }
+
     pub fn start(self, tx: Sender<Event>) {
         use self::Event::*;
         self.reader.thread().unpark();
-        while !self.is_finished.load(Ordering::Relaxed) {
+        loop {
             thread::sleep(Duration::from_millis(DUMP_INTERVAL_MS));
+            // Checked before dumping so that the last lines are not left in
+            // the chunk.
+            let is_finished = self.is_finished.load(Ordering::Relaxed);
             let mut chunk = self.chunk.lock().unwrap();
-            if chunk.len() > 0 {
-                self.line_storage.write().unwrap().put_chunk(chunk.clone());
+            // Dropped duplicates are counted even if no lines are read.
+            if !chunk.is_empty() || !chunk.header().is_empty() || chunk.num_duplicates() > 0 {
+                self.line_storage.write().unwrap().put_chunk(&chunk);
                 chunk.clear();
                 drop(chunk);
                 if tx.send(DidReadChunk).is_err() {
                     return;
                 }
             }
+            if is_finished {
+                break;
+            }
         }
         let _ = tx.send(DidFinish);
     }
@@ -58,33 +95,448 @@ impl Reader {
 
 fn spawn_parked_reader(
     config: Config,
-    chunk: Arc<Mutex<Vec<Arc<Line>>>>,
+    chunk: Arc<Mutex<LineStorage>>,
     is_finished: Arc<AtomicBool>,
+    source_command: Option<Arc<SourceCommand>>,
 ) -> thread::JoinHandle<()> {
     spawn_with_name("reader::reader", move || {
         thread::park();
-        let mut buf = Vec::with_capacity(1024);
-        let mut buf_reader = BufReader::new(config.input_source());
-        loop {
-            buf.clear();
-            let res = buf_reader.read_until(0xA, &mut buf);
-            match res {
-                Ok(_) if !buf.is_empty() => {
-                    if buf.last() == Some(&0xA) {
-                        buf.pop().unwrap();
-                    }
-                    let mut chunk = chunk.lock().unwrap();
-                    chunk.push(Arc::new(Line::new(buf.clone())));
-                    drop(chunk);
-                }
-                Ok(_) => {
-                    break;
-                }
-                Err(_) => {
-                    break;
+        // Reading never finishes as the command can be rerun.
+        if let Some(source_command) = source_command {
+            source_command.run(config.initial_query().unwrap_or(""));
+            return;
+        }
+        let delimiter = config.input_delimiter();
+        let encoding = config.encoding();
+        let paths = config.input_file_paths();
+        if paths.is_empty() {
+            read_source(config.input_source(), 0, encoding, delimiter, &chunk);
+        } else if config.follows() {
+            follow_files(paths, encoding, delimiter, &chunk);
+        }
+        // Files are read one after another, each line remembering the index
+        // of its file as the origin.
+        for (origin, path) in paths.iter().enumerate() {
+            // Regular files are mapped and their lines indexed in place
+            // rather than copied, unless they must be decoded as read.
+            let mmap = if config.uses_mmap() {
+                Mmap::open(path)
+            } else {
+                None
+            };
+            if let Some(mmap) = mmap {
+                let encoding = encoding.resolve(&mmap[..cmp::min(mmap.len(), SAMPLE_LEN)]);
+                if !encoding::is_utf16(encoding) {
+                    chunk.lock().unwrap().add_source(
+                        origin,
+                        line_encoding(encoding),
+                        Some(Arc::new(mmap)),
+                    );
+                    while chunk.lock().unwrap().index_mapped_lines(LINES_PER_INDEX) {}
+                    continue;
                 }
             }
+            if let Ok(file) = File::open(path) {
+                read_source(file, origin, encoding, delimiter, &chunk);
+            }
         }
         is_finished.store(true, Ordering::Relaxed);
     })
 }
+
+/// Runs the command given by `--source-cmd` and reads its output like
+/// standard input, and reruns it on reload.
+pub struct SourceCommand {
+    chunk: Arc<Mutex<LineStorage>>,
+    command: String,
+    delimiter: u8,
+    encoding: InputEncoding,
+    running: Mutex<Option<(Child, thread::JoinHandle<()>)>>,
+}
+
+impl SourceCommand {
+    fn new(config: &Config, command: &str, chunk: Arc<Mutex<LineStorage>>) -> Self {
+        SourceCommand {
+            chunk,
+            command: command.to_owned(),
+            delimiter: config.input_delimiter(),
+            encoding: config.encoding(),
+            running: Mutex::new(None),
+        }
+    }
+
+    /// Runs the command with `{q}` in it replaced by `query` quoted for the
+    /// shell.
+    pub fn run(&self, query: &str) {
+        let command = self.command.replace("{q}", &quote_for_shell(query));
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .process_group(0)
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(_) => return,
+        };
+        let stdout = child.stdout.take().unwrap();
+        let chunk = self.chunk.clone();
+        let (delimiter, encoding) = (self.delimiter, self.encoding);
+        let reader = spawn_with_name("reader::source_command", move || {
+            read_source(stdout, 0, encoding, delimiter, &chunk);
+        });
+        *self.running.lock().unwrap() = Some((child, reader));
+    }
+
+    /// Kills the command if it is running and drops the lines read from it
+    /// that are not dumped yet. The header lines are taken again from the
+    /// next run.
+    pub fn stop(&self) {
+        if let Some((mut child, reader)) = self.running.lock().unwrap().take() {
+            // The whole process group is killed so that the output is closed
+            // even if the shell started other processes, e.g. for a pipeline.
+            unsafe {
+                libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+            }
+            let _ = child.wait();
+            let _ = reader.join();
+        }
+        self.chunk.lock().unwrap().reset();
+    }
+}
+
+// Quotes `s` in single quotes so that the shell takes it literally.
+fn quote_for_shell(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+// Reads the files and then keeps reading lines appended to them. Never
+// returns.
+fn follow_files(
+    paths: &[String],
+    encoding: InputEncoding,
+    delimiter: u8,
+    chunk: &Mutex<LineStorage>,
+) -> ! {
+    let mut followers: Vec<Follower> = paths
+        .iter()
+        .enumerate()
+        .map(|(origin, path)| Follower::new(origin, path, encoding, delimiter))
+        .collect();
+    loop {
+        for follower in followers.iter_mut() {
+            follower.read_lines(chunk);
+        }
+        thread::sleep(Duration::from_millis(FOLLOW_INTERVAL_MS));
+    }
+}
+
+// Reads a file like `tail -F`: lines are read as they are appended, and the
+// file is reopened when it is truncated or replaced, e.g. by log rotation.
+struct Follower {
+    delimiter: u8,
+    encoding: InputEncoding,
+    file: Option<(File, u64)>, // and its inode number
+    origin: usize,
+    path: String,
+    position: u64,
+    // Made once the first bytes are read and the encoding is known.
+    splitter: Option<LineSplitter>,
+}
+
+impl Follower {
+    fn new(origin: usize, path: &str, encoding: InputEncoding, delimiter: u8) -> Self {
+        Follower {
+            delimiter,
+            encoding,
+            file: None,
+            origin,
+            path: path.to_owned(),
+            position: 0,
+            splitter: None,
+        }
+    }
+
+    fn read_lines(&mut self, chunk: &Mutex<LineStorage>) {
+        if self.is_stale() {
+            self.file = File::open(&self.path)
+                .and_then(|file| Ok((file.metadata()?.ino(), file)))
+                .map(|(ino, file)| (file, ino))
+                .ok();
+            self.position = 0;
+            self.splitter = None;
+        }
+        let file = match self.file {
+            Some((ref mut file, _)) => file,
+            None => return,
+        };
+        let mut bytes = Vec::new();
+        let _ = file.read_to_end(&mut bytes);
+        if bytes.is_empty() {
+            return;
+        }
+        self.position += bytes.len() as u64;
+        let (origin, encoding, delimiter) = (self.origin, self.encoding, self.delimiter);
+        self.splitter
+            .get_or_insert_with(|| LineSplitter::new(origin, encoding.resolve(&bytes), delimiter))
+            .put(&bytes, chunk);
+    }
+
+    // Returns true if the file is not open yet, or the path now points to a
+    // different or truncated file.
+    fn is_stale(&self) -> bool {
+        let ino = match self.file {
+            Some((_, ino)) => ino,
+            None => return true,
+        };
+        match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.ino() != ino || metadata.len() < self.position,
+            Err(_) => false,
+        }
+    }
+}
+
+// Splits bytes read in blocks into lines. A partial line is kept until its
+// delimiter is read. UTF-16 lines are split on whole code units and stored
+// as they are, without the BOM, which belongs to no line.
+struct LineSplitter {
+    buf: Vec<u8>,
+    delimiter: u8,
+    encoding: &'static Encoding,
+    // True until the BOM, if any, is dropped.
+    is_at_start: bool,
+    origin: usize,
+}
+
+impl LineSplitter {
+    fn new(origin: usize, encoding: &'static Encoding, delimiter: u8) -> Self {
+        LineSplitter {
+            buf: Vec::with_capacity(1024),
+            delimiter,
+            encoding,
+            is_at_start: encoding::is_utf16(encoding),
+            origin,
+        }
+    }
+
+    fn put(&mut self, bytes: &[u8], chunk: &Mutex<LineStorage>) {
+        self.buf.extend_from_slice(bytes);
+        if self.is_at_start {
+            if self.buf.len() < 2 {
+                return;
+            }
+            self.drop_bom();
+        }
+        let mut start = 0;
+        let mut chunk = chunk.lock().unwrap();
+        chunk.add_source(self.origin, line_encoding(self.encoding), None);
+        while let Some(len) = self.line_len(&self.buf[start..]) {
+            chunk.push(&self.buf[start..start + len]);
+            start += len + self.delimiter_len();
+        }
+        self.buf.drain(..start);
+    }
+
+    // Adds the last line, which has no delimiter.
+    fn finish(&mut self, chunk: &Mutex<LineStorage>) {
+        if self.is_at_start {
+            self.drop_bom();
+        }
+        if !self.buf.is_empty() {
+            let mut chunk = chunk.lock().unwrap();
+            chunk.add_source(self.origin, line_encoding(self.encoding), None);
+            chunk.push(&self.buf);
+            self.buf.clear();
+        }
+    }
+
+    fn drop_bom(&mut self) {
+        if let Some((encoding, len)) = Encoding::for_bom(&self.buf) {
+            if encoding == self.encoding {
+                self.buf.drain(..len);
+            }
+        }
+        self.is_at_start = false;
+    }
+
+    // Returns the length of the line `bytes` starts with if its delimiter is
+    // read.
+    fn line_len(&self, bytes: &[u8]) -> Option<usize> {
+        if !encoding::is_utf16(self.encoding) {
+            return memchr(self.delimiter, bytes);
+        }
+        let unit = if self.encoding == UTF_16BE {
+            [0, self.delimiter]
+        } else {
+            [self.delimiter, 0]
+        };
+        bytes.chunks_exact(2).position(|u| u == unit).map(|i| i * 2)
+    }
+
+    fn delimiter_len(&self) -> usize {
+        if encoding::is_utf16(self.encoding) {
+            2
+        } else {
+            1
+        }
+    }
+}
+
+// Reads the lines of `source` in the encoding it turns out to be in.
+fn read_source<R: io::Read>(
+    source: R,
+    origin: usize,
+    encoding: InputEncoding,
+    delimiter: u8,
+    chunk: &Mutex<LineStorage>,
+) {
+    let mut reader = BufReader::with_capacity(SAMPLE_LEN, source);
+    let encoding = encoding.resolve(reader.fill_buf().unwrap_or(&[]));
+    if !encoding::is_utf16(encoding) {
+        chunk
+            .lock()
+            .unwrap()
+            .add_source(origin, line_encoding(encoding), None);
+        read_lines(reader, delimiter, chunk);
+        return;
+    }
+    let mut splitter = LineSplitter::new(origin, encoding, delimiter);
+    loop {
+        let n = match reader.fill_buf() {
+            Ok(bytes) if !bytes.is_empty() => {
+                splitter.put(bytes, chunk);
+                bytes.len()
+            }
+            _ => break,
+        };
+        reader.consume(n);
+    }
+    splitter.finish(chunk);
+}
+
+fn read_lines<R: BufRead>(mut reader: R, delimiter: u8, chunk: &Mutex<LineStorage>) {
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        buf.clear();
+        let res = reader.read_until(delimiter, &mut buf);
+        match res {
+            Ok(_) if !buf.is_empty() => {
+                if buf.last() == Some(&delimiter) {
+                    buf.pop().unwrap();
+                }
+                chunk.lock().unwrap().push(&buf);
+            }
+            Ok(_) => {
+                break;
+            }
+            Err(_) => {
+                break;
+            }
+        }
+    }
+}
+
+// Returns the encoding lines in `encoding` are stored with, which is `None`
+// for UTF-8 as they need no decoding.
+fn line_encoding(encoding: &'static Encoding) -> Option<&'static Encoding> {
+    if encoding == UTF_8 {
+        None
+    } else {
+        Some(encoding)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::UTF_16LE;
+
+    fn split(
+        encoding: &'static Encoding,
+        delimiter: u8,
+        bytes: &[u8],
+        block_len: usize,
+    ) -> LineStorage {
+        let args: &[&str] = if delimiter == b'\0' {
+            &["--read0"]
+        } else {
+            &[]
+        };
+        let chunk = Mutex::new(LineStorage::new(&Config::with_test_args(args)));
+        let mut splitter = LineSplitter::new(0, encoding, delimiter);
+        for block in bytes.chunks(block_len) {
+            splitter.put(block, &chunk);
+        }
+        splitter.finish(&chunk);
+        chunk.into_inner().unwrap()
+    }
+
+    fn raw_lines(line_storage: &LineStorage) -> Vec<Vec<u8>> {
+        (0..line_storage.len())
+            .map(|i| line_storage.get_unchecked(i).as_bytes().to_vec())
+            .collect()
+    }
+
+    fn str_lines(line_storage: &LineStorage) -> Vec<String> {
+        (0..line_storage.len())
+            .map(|i| line_storage.get_unchecked(i).to_str().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn utf16_lines_are_stored_as_read_without_bom() {
+        for &encoding in [UTF_16LE, UTF_16BE].iter() {
+            let mut bytes = encoding::encode_utf16("\u{feff}", encoding);
+            bytes.extend(encoding::encode_utf16("foo\n日本\n", encoding));
+            // Odd block lengths split code units between blocks.
+            for &block_len in [1, 3, 1024].iter() {
+                let storage = split(encoding, b'\n', &bytes, block_len);
+                assert_eq!(
+                    raw_lines(&storage),
+                    vec![
+                        encoding::encode_utf16("foo", encoding),
+                        encoding::encode_utf16("日本", encoding),
+                    ]
+                );
+                assert_eq!(str_lines(&storage), vec!["foo", "日本"]);
+                assert_eq!(storage.get_unchecked(0).encoding(), Some(encoding));
+            }
+        }
+    }
+
+    #[test]
+    fn newline_byte_inside_a_code_unit_does_not_split() {
+        // Both U+0A0A and U+010A contain a `\n` byte in UTF-16LE.
+        let bytes = encoding::encode_utf16("\u{a0a}\u{10a}x\n", UTF_16LE);
+        let storage = split(UTF_16LE, b'\n', &bytes, 1024);
+        assert_eq!(str_lines(&storage), vec!["\u{a0a}\u{10a}x"]);
+        // `《` ends with a `\n` byte in UTF-16BE.
+        let bytes = encoding::encode_utf16("《\n》", UTF_16BE);
+        let storage = split(UTF_16BE, b'\n', &bytes, 1024);
+        assert_eq!(str_lines(&storage), vec!["《", "》"]);
+    }
+
+    #[test]
+    fn utf16_lines_are_split_on_nul_with_read0() {
+        let bytes = encoding::encode_utf16("foo\nbar\0baz", UTF_16BE);
+        let storage = split(UTF_16BE, b'\0', &bytes, 5);
+        assert_eq!(str_lines(&storage), vec!["foo\nbar", "baz"]);
+    }
+
+    #[test]
+    fn last_line_without_delimiter_is_added_on_finish() {
+        let storage = split(
+            UTF_16LE,
+            b'\n',
+            &encoding::encode_utf16("a\nb", UTF_16LE),
+            2,
+        );
+        assert_eq!(str_lines(&storage), vec!["a", "b"]);
+        let storage = split(UTF_16LE, b'\n', &encoding::encode_utf16("a\n", UTF_16LE), 2);
+        assert_eq!(str_lines(&storage), vec!["a"]);
+        let storage = split(UTF_16LE, b'\n', b"", 2);
+        assert!(storage.is_empty());
+    }
+}
@@ -0,0 +1,347 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::sync::Arc;
+
+use crate::config::Config;
+
+const MAX_DICTIONARY_EXPANSIONS: usize = 32;
+
+/// Expands romaji query terms into kana (and optionally kanji) alternatives
+/// so that `tokyo` also finds `ときょ`, `トキョ` and, with a dictionary,
+/// `東京`.
+pub struct Romaji {
+    dictionary: Option<Arc<Dictionary>>,
+}
+
+/// Words by their readings, given by `--romaji-dict`.
+pub struct Dictionary {
+    // Readings are in hiragana without long vowels, so that `tokyo` finds
+    // the words read `とうきょう`.
+    entries: Vec<(String, Vec<String>)>,
+}
+
+impl Romaji {
+    pub fn new(config: &Config) -> Option<Self> {
+        if !config.uses_romaji() {
+            return None;
+        }
+        Some(Romaji {
+            dictionary: config.romaji_dictionary(),
+        })
+    }
+
+    /// Returns `token` followed by its alternatives. Modifiers (`'`, `^` and
+    /// `$`) are carried over to each alternative; inverse terms are not
+    /// expanded as their alternatives would have to be ANDed.
+    pub fn expand(&self, token: &str) -> Vec<String> {
+        let mut alternatives = vec![token.to_owned()];
+        if token.starts_with('!') {
+            return alternatives;
+        }
+        let prefix_len = if token.starts_with('\'') || token.starts_with('^') {
+            1
+        } else {
+            0
+        };
+        let suffix_len = if token.len() > prefix_len + 1 && token.ends_with('$') {
+            1
+        } else {
+            0
+        };
+        let prefix = &token[..prefix_len];
+        let suffix = &token[token.len() - suffix_len..];
+        let body = &token[prefix_len..token.len() - suffix_len];
+        let hiragana = match to_hiragana(body) {
+            Some(hiragana) => hiragana,
+            None => return alternatives,
+        };
+        let katakana: String = hiragana.chars().map(to_katakana).collect();
+        alternatives.push(format!("{}{}{}", prefix, hiragana, suffix));
+        alternatives.push(format!("{}{}{}", prefix, katakana, suffix));
+        if let Some(ref dictionary) = self.dictionary {
+            let reading = without_long_vowels(&hiragana);
+            let words = dictionary
+                .entries
+                .iter()
+                .filter(|(entry_reading, _)| entry_reading.starts_with(&reading))
+                .flat_map(|(_, words)| words)
+                .take(MAX_DICTIONARY_EXPANSIONS);
+            for word in words {
+                alternatives.push(format!("{}{}{}", prefix, word, suffix));
+            }
+        }
+        alternatives
+    }
+}
+
+impl Dictionary {
+    /// Reads the dictionary at `path`. Each line is a reading in kana
+    /// followed by words separated by whitespace or `/`, e.g.
+    /// `とうきょう /東京/`. Lines starting with `;` or `#` are comments.
+    pub fn read(path: &str) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut entries = Vec::new();
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| format!("{}: {}", path, e))?;
+            if line.starts_with(';') || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line
+                .split(|ch: char| ch.is_whitespace() || ch == '/')
+                .filter(|s| !s.is_empty());
+            if let Some(reading) = fields.next() {
+                let words: Vec<String> = fields.map(|s| s.to_owned()).collect();
+                if words.is_empty() || !reading.chars().all(is_kana) {
+                    return Err(format!(
+                        "{}:{}: expected a reading in kana followed by words",
+                        path,
+                        n + 1
+                    ));
+                }
+                let reading: String = reading.chars().map(to_hiragana_char).collect();
+                entries.push((without_long_vowels(&reading), words));
+            }
+        }
+        Ok(Dictionary { entries })
+    }
+}
+
+/// Converts romaji to hiragana. A trailing incomplete syllable, which is
+/// common while typing, is dropped. Returns `None` if `s` is not romaji.
+fn to_hiragana(s: &str) -> Option<String> {
+    if s.is_empty()
+        || !s
+            .bytes()
+            .all(|b| b.is_ascii_alphabetic() || b == b'-' || b == b'\'')
+    {
+        return None;
+    }
+    let s = s.to_ascii_lowercase();
+    let mut hiragana = String::new();
+    let mut rest = &s[..];
+    while !rest.is_empty() {
+        if let Some((romaji, kana)) = lookup(rest) {
+            hiragana.push_str(kana);
+            rest = &rest[romaji.len()..];
+            continue;
+        }
+        let bytes = rest.as_bytes();
+        let next = bytes.get(1).cloned();
+        let ends_syllable = |b: Option<&u8>| b.map(|&b| !is_vowel(b) && b != b'y').unwrap_or(true);
+        if bytes[0] == b'n' && ends_syllable(bytes.get(1)) {
+            // `nn` is a single `ん` unless the second `n` starts `na`, `nya`...
+            let len = if next == Some(b'n') && ends_syllable(bytes.get(2)) {
+                2
+            } else {
+                1
+            };
+            hiragana.push('ん');
+            rest = &rest[len..];
+        } else if !is_vowel(bytes[0])
+            && (next == Some(bytes[0]) || (bytes[0] == b't' && next == Some(b'c')))
+        {
+            hiragana.push('っ');
+            rest = &rest[1..];
+        } else if ROMAJI_TABLE
+            .iter()
+            .any(|(romaji, _)| romaji.starts_with(rest))
+        {
+            break;
+        } else {
+            return None;
+        }
+    }
+    if hiragana.is_empty() {
+        None
+    } else {
+        Some(hiragana)
+    }
+}
+
+fn lookup(s: &str) -> Option<(&'static str, &'static str)> {
+    (1..=4).rev().find_map(|len| {
+        let head = s.get(..len)?;
+        ROMAJI_TABLE
+            .iter()
+            .find(|(romaji, _)| *romaji == head)
+            .cloned()
+    })
+}
+
+fn is_vowel(b: u8) -> bool {
+    b"aiueo".contains(&b)
+}
+
+fn to_katakana(ch: char) -> char {
+    match ch {
+        'ぁ'..='ゖ' => std::char::from_u32(ch as u32 + 0x60).unwrap(),
+        _ => ch,
+    }
+}
+
+// Drops `ー` and the `う` or `お` that lengthen the vowel before them, which
+// romaji often leaves out, e.g. `とうきょう` becomes `ときょ` as in `tokyo`.
+fn without_long_vowels(hiragana: &str) -> String {
+    let mut s = String::with_capacity(hiragana.len());
+    let mut last = None;
+    for ch in hiragana.chars() {
+        let is_long_vowel = match ch {
+            'ー' => true,
+            'う' => last.is_some_and(|last| is_o_row(last) || is_u_row(last)),
+            'お' => last.is_some_and(is_o_row),
+            _ => false,
+        };
+        if !is_long_vowel {
+            s.push(ch);
+        }
+        last = Some(ch);
+    }
+    s
+}
+
+fn is_o_row(ch: char) -> bool {
+    "おこごそぞとどのほぼぽもよろをょぉ".contains(ch)
+}
+
+fn is_u_row(ch: char) -> bool {
+    "うくぐすずつづぬふぶぷむゆるゅぅゔ".contains(ch)
+}
+
+fn is_kana(ch: char) -> bool {
+    matches!(ch, 'ぁ'..='ゖ' | 'ァ'..='ヶ' | 'ー')
+}
+
+fn to_hiragana_char(ch: char) -> char {
+    match ch {
+        'ァ'..='ヶ' => std::char::from_u32(ch as u32 - 0x60).unwrap(),
+        _ => ch,
+    }
+}
+
+#[rustfmt::skip]
+const ROMAJI_TABLE: &[(&str, &str)] = &[
+    ("a", "あ"), ("i", "い"), ("u", "う"), ("e", "え"), ("o", "お"),
+    ("ka", "か"), ("ki", "き"), ("ku", "く"), ("ke", "け"), ("ko", "こ"),
+    ("ca", "か"), ("cu", "く"), ("co", "こ"),
+    ("sa", "さ"), ("si", "し"), ("shi", "し"), ("su", "す"), ("se", "せ"), ("so", "そ"),
+    ("ta", "た"), ("ti", "ち"), ("chi", "ち"), ("tu", "つ"), ("tsu", "つ"), ("te", "て"),
+    ("to", "と"),
+    ("na", "な"), ("ni", "に"), ("nu", "ぬ"), ("ne", "ね"), ("no", "の"),
+    ("ha", "は"), ("hi", "ひ"), ("hu", "ふ"), ("fu", "ふ"), ("he", "へ"), ("ho", "ほ"),
+    ("ma", "ま"), ("mi", "み"), ("mu", "む"), ("me", "め"), ("mo", "も"),
+    ("ya", "や"), ("yu", "ゆ"), ("ye", "いぇ"), ("yo", "よ"),
+    ("ra", "ら"), ("ri", "り"), ("ru", "る"), ("re", "れ"), ("ro", "ろ"),
+    ("wa", "わ"), ("wi", "うぃ"), ("we", "うぇ"), ("wo", "を"),
+    ("ga", "が"), ("gi", "ぎ"), ("gu", "ぐ"), ("ge", "げ"), ("go", "ご"),
+    ("za", "ざ"), ("zi", "じ"), ("ji", "じ"), ("zu", "ず"), ("ze", "ぜ"), ("zo", "ぞ"),
+    ("da", "だ"), ("di", "ぢ"), ("du", "づ"), ("de", "で"), ("do", "ど"),
+    ("ba", "ば"), ("bi", "び"), ("bu", "ぶ"), ("be", "べ"), ("bo", "ぼ"),
+    ("pa", "ぱ"), ("pi", "ぴ"), ("pu", "ぷ"), ("pe", "ぺ"), ("po", "ぽ"),
+    ("va", "ゔぁ"), ("vi", "ゔぃ"), ("vu", "ゔ"), ("ve", "ゔぇ"), ("vo", "ゔぉ"),
+    ("fa", "ふぁ"), ("fi", "ふぃ"), ("fe", "ふぇ"), ("fo", "ふぉ"),
+    ("kya", "きゃ"), ("kyu", "きゅ"), ("kyo", "きょ"),
+    ("sya", "しゃ"), ("syu", "しゅ"), ("syo", "しょ"),
+    ("sha", "しゃ"), ("shu", "しゅ"), ("she", "しぇ"), ("sho", "しょ"),
+    ("tya", "ちゃ"), ("tyu", "ちゅ"), ("tyo", "ちょ"),
+    ("cha", "ちゃ"), ("chu", "ちゅ"), ("che", "ちぇ"), ("cho", "ちょ"),
+    ("cya", "ちゃ"), ("cyu", "ちゅ"), ("cyo", "ちょ"),
+    ("thi", "てぃ"), ("dhi", "でぃ"),
+    ("nya", "にゃ"), ("nyu", "にゅ"), ("nyo", "にょ"),
+    ("hya", "ひゃ"), ("hyu", "ひゅ"), ("hyo", "ひょ"),
+    ("mya", "みゃ"), ("myu", "みゅ"), ("myo", "みょ"),
+    ("rya", "りゃ"), ("ryu", "りゅ"), ("ryo", "りょ"),
+    ("gya", "ぎゃ"), ("gyu", "ぎゅ"), ("gyo", "ぎょ"),
+    ("ja", "じゃ"), ("ju", "じゅ"), ("je", "じぇ"), ("jo", "じょ"),
+    ("jya", "じゃ"), ("jyu", "じゅ"), ("jyo", "じょ"),
+    ("zya", "じゃ"), ("zyu", "じゅ"), ("zyo", "じょ"),
+    ("dya", "ぢゃ"), ("dyu", "ぢゅ"), ("dyo", "ぢょ"),
+    ("bya", "びゃ"), ("byu", "びゅ"), ("byo", "びょ"),
+    ("pya", "ぴゃ"), ("pyu", "ぴゅ"), ("pyo", "ぴょ"),
+    ("xa", "ぁ"), ("xi", "ぃ"), ("xu", "ぅ"), ("xe", "ぇ"), ("xo", "ぉ"),
+    ("la", "ぁ"), ("li", "ぃ"), ("lu", "ぅ"), ("le", "ぇ"), ("lo", "ぉ"),
+    ("xya", "ゃ"), ("xyu", "ゅ"), ("xyo", "ょ"),
+    ("lya", "ゃ"), ("lyu", "ゅ"), ("lyo", "ょ"),
+    ("xtu", "っ"), ("xtsu", "っ"), ("ltu", "っ"), ("ltsu", "っ"),
+    ("n'", "ん"), ("-", "ー"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn read(name: &str, contents: &str) -> Result<Vec<(String, Vec<String>)>, String> {
+        let path = env::temp_dir().join(format!("eru-romaji-{}-{}", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        let dictionary = Dictionary::read(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        dictionary.map(|dictionary| dictionary.entries)
+    }
+
+    fn romaji(dictionary: &str) -> Romaji {
+        Romaji {
+            dictionary: Some(Arc::new(Dictionary {
+                entries: read("expand", dictionary).unwrap(),
+            })),
+        }
+    }
+
+    #[test]
+    fn dictionary_lists_words_by_hiragana_reading() {
+        let dictionary =
+            read("ok", "; comment\n\nトウキョウ /東京/\nおおさか 大阪 逢坂\n").unwrap();
+        assert_eq!(
+            dictionary,
+            vec![
+                ("ときょ".to_owned(), vec!["東京".to_owned()]),
+                (
+                    "おさか".to_owned(),
+                    vec!["大阪".to_owned(), "逢坂".to_owned()]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_entry_is_an_error_naming_its_line() {
+        let error = read("no-words", "とうきょう 東京\nおおさか\n").unwrap_err();
+        assert!(error.ends_with(":2: expected a reading in kana followed by words"));
+        assert!(read("not-kana", "tokyo 東京\n").is_err());
+    }
+
+    #[test]
+    fn missing_dictionary_is_an_error_naming_its_path() {
+        let error = Dictionary::read("/nonexistent/eru-dictionary")
+            .err()
+            .unwrap();
+        assert!(error.starts_with("/nonexistent/eru-dictionary: "));
+    }
+
+    #[test]
+    fn words_are_found_without_long_vowels() {
+        let romaji = romaji("とうきょう 東京\nおおさか 大阪\nきょうと 京都\nコーヒー 珈琲\n");
+        assert_eq!(
+            romaji.expand("tokyo"),
+            ["tokyo", "ときょ", "トキョ", "東京"]
+        );
+        assert_eq!(
+            romaji.expand("toukyou"),
+            ["toukyou", "とうきょう", "トウキョウ", "東京"]
+        );
+        assert_eq!(romaji.expand("osaka")[3..], ["大阪"]);
+        assert_eq!(romaji.expand("kyoto")[3..], ["京都"]);
+        assert_eq!(romaji.expand("kohi")[3..], ["珈琲"]);
+        assert_eq!(romaji.expand("^kyo")[3..], ["^京都"]);
+    }
+
+    #[test]
+    fn long_vowels_are_dropped_after_their_rows_only() {
+        assert_eq!(without_long_vowels("とうきょう"), "ときょ");
+        assert_eq!(without_long_vowels("おおさか"), "おさか");
+        assert_eq!(without_long_vowels("こーひー"), "こひ");
+        assert_eq!(without_long_vowels("かう"), "かう");
+        assert_eq!(without_long_vowels("けお"), "けお");
+        assert_eq!(without_long_vowels("うう"), "う");
+    }
+}
@@ -1,27 +1,48 @@
 use ncurses as nc;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
 use std::ffi::CString;
 
+use crate::ansi::Style;
+use crate::config::Config;
 use crate::libc_aux;
 use crate::screen_data::ScreenData;
-use crate::window::{Gutter, ListView, MiniBuf, StatusLine};
+use crate::window::{Gutter, Header, ListView, MiniBuf, StatusLine};
 use crate::window::{Rect, Window};
 
+const COLOR_PAIR_MATCH: i16 = 1;
+// Pair numbers above this do not fit in the attributes without wide support.
+const MAX_COLOR_PAIRS: i32 = 256;
+
+thread_local! {
+    // The pairs allocated for the colors of styles, by foreground and
+    // background.
+    static COLOR_PAIRS: RefCell<HashMap<(i16, i16), i16>> = RefCell::new(HashMap::new());
+}
+
 pub struct Screen {
     gutter: Window,
+    header: Window,
+    header_height: i32,
     list_view: Window,
     mini_buf: Window,
     status_line: Window,
 }
 
 impl Screen {
-    pub fn new() -> Self {
-        let layout = Layout::new();
+    pub fn new(config: &Config) -> Self {
+        let header_height = header_height(config);
+        let layout = Layout::new(header_height);
         let gutter = Window::new(Gutter, layout.gutter_rect);
+        let header = Window::new(Header, layout.header_rect);
         let list_view = Window::new(ListView, layout.list_view_rect);
         let mini_buf = Window::new(MiniBuf, layout.mini_buf_rect);
         let status_line = Window::new(StatusLine, layout.status_line_rect);
         Screen {
             gutter,
+            header,
+            header_height,
             list_view,
             mini_buf,
             status_line,
@@ -31,6 +52,7 @@ impl Screen {
     pub fn update(&self, sd: ScreenData) {
         for win in [
             &self.gutter,
+            &self.header,
             &self.list_view,
             &self.mini_buf,
             &self.status_line,
@@ -54,8 +76,9 @@ impl Screen {
         nc::endwin();
         nc::initscr();
 
-        let layout = Layout::new();
+        let layout = Layout::new(self.header_height);
         self.gutter.resize(layout.gutter_rect);
+        self.header.resize(layout.header_rect);
         self.list_view.resize(layout.list_view_rect);
         self.mini_buf.resize(layout.mini_buf_rect);
         self.status_line.resize(layout.status_line_rect);
@@ -64,13 +87,14 @@ impl Screen {
 
 struct Layout {
     gutter_rect: Rect,
+    header_rect: Rect,
     list_view_rect: Rect,
     mini_buf_rect: Rect,
     status_line_rect: Rect,
 }
 
 impl Layout {
-    fn new() -> Layout {
+    fn new(header_height: i32) -> Layout {
         let mut max_y = 0i32;
         let mut max_x = 0i32;
         nc::getmaxyx(nc::stdscr(), &mut max_y, &mut max_x);
@@ -79,20 +103,37 @@ impl Layout {
         let mini_buf_height = 1;
         let status_line_height = 1;
 
+        // At least a row is left for the list.
+        let header_height = cmp::max(
+            cmp::min(
+                header_height,
+                max_y - mini_buf_height - status_line_height - 1,
+            ),
+            0,
+        );
+        let list_view_y = mini_buf_height + status_line_height + header_height;
+
         let gutter_rect = Rect {
-            height: max_y - mini_buf_height - status_line_height,
+            height: max_y - list_view_y,
             width: gutter_width,
-            y: mini_buf_height + status_line_height,
+            y: list_view_y,
             x: 0,
         };
 
-        let list_view_rect = Rect {
-            height: max_y - mini_buf_height - status_line_height,
+        let header_rect = Rect {
+            height: header_height,
             width: max_x - gutter_width,
             y: mini_buf_height + status_line_height,
             x: gutter_width,
         };
 
+        let list_view_rect = Rect {
+            height: max_y - list_view_y,
+            width: max_x - gutter_width,
+            y: list_view_y,
+            x: gutter_width,
+        };
+
         let mini_buf_rect = Rect {
             height: mini_buf_height,
             width: max_x,
@@ -109,6 +150,7 @@ impl Layout {
 
         Layout {
             gutter_rect,
+            header_rect,
             list_view_rect,
             mini_buf_rect,
             status_line_rect,
@@ -116,6 +158,13 @@ impl Layout {
     }
 }
 
+// Returns the number of rows of the header, which are for the `--header`
+// text followed by the header lines.
+fn header_height(config: &Config) -> i32 {
+    let text_height = config.header().map(|s| s.lines().count()).unwrap_or(0);
+    (text_height + config.header_lines()) as i32
+}
+
 pub fn initialize() {
     let s = CString::new("").unwrap();
     unsafe {
@@ -126,6 +175,11 @@ pub fn initialize() {
     nc::initscr();
     nc::noecho();
     nc::raw();
+    if nc::has_colors() {
+        nc::start_color();
+        nc::use_default_colors();
+        nc::init_pair(COLOR_PAIR_MATCH, nc::COLOR_YELLOW, -1);
+    }
 }
 
 pub fn finalize() {
@@ -134,3 +188,69 @@ pub fn finalize() {
         libc::dup2(3, 1);
     }
 }
+
+pub fn match_attr() -> nc::attr_t {
+    if nc::has_colors() {
+        nc::A_BOLD() | nc::COLOR_PAIR(COLOR_PAIR_MATCH)
+    } else {
+        nc::A_BOLD() | nc::A_UNDERLINE()
+    }
+}
+
+/// Returns the attributes to draw text in `style` with, in the colors of
+/// matches instead of its own if `is_match`.
+pub fn style_attr(style: Style, is_match: bool) -> nc::attr_t {
+    let mut attr = 0;
+    for &(is_set, a) in [
+        (style.blinks, nc::A_BLINK()),
+        (style.is_bold, nc::A_BOLD()),
+        (style.is_dim, nc::A_DIM()),
+        (style.is_italic, nc::A_ITALIC()),
+        (style.is_reversed, nc::A_REVERSE()),
+        (style.is_underlined, nc::A_UNDERLINE()),
+    ]
+    .iter()
+    {
+        if is_set {
+            attr |= a;
+        }
+    }
+    if is_match {
+        attr | match_attr()
+    } else {
+        attr | color_pair_attr(style.fg, style.bg)
+    }
+}
+
+// Returns the attribute of the color pair of `fg` and `bg`, allocating it on
+// first use. Colors are left as they are once pairs run out.
+fn color_pair_attr(fg: Option<u8>, bg: Option<u8>) -> nc::attr_t {
+    if !nc::has_colors() || (fg.is_none() && bg.is_none()) {
+        return 0;
+    }
+    let colors = (to_color(fg), to_color(bg));
+    COLOR_PAIRS.with(|pairs| {
+        let mut pairs = pairs.borrow_mut();
+        if let Some(&pair) = pairs.get(&colors) {
+            return nc::COLOR_PAIR(pair);
+        }
+        let pair = COLOR_PAIR_MATCH as i32 + 1 + pairs.len() as i32;
+        if pair >= nc::COLOR_PAIRS().min(MAX_COLOR_PAIRS) {
+            return 0;
+        }
+        nc::init_pair(pair as i16, colors.0, colors.1);
+        pairs.insert(colors, pair as i16);
+        nc::COLOR_PAIR(pair as i16)
+    })
+}
+
+// Returns the terminal color for the palette index `color`, using the basic
+// colors for the bright ones on terminals with 8 colors.
+fn to_color(color: Option<u8>) -> i16 {
+    let colors = nc::COLORS();
+    match color {
+        Some(color) if (color as i32) < colors => color as i16,
+        Some(color) if color < 16 && colors >= 8 => color as i16 - 8,
+        _ => -1,
+    }
+}
@@ -1,15 +1,22 @@
 use std::sync::Arc;
 
-use crate::item::Item;
+use crate::ansi::Styles;
+use crate::match_info_cache::CacheStats;
 
 #[derive(Clone)]
 pub struct ScreenData {
+    pub cache_stats: Option<CacheStats>,
     pub cursor_index: usize,
+    pub header: Vec<String>,
     pub highlighted_row: Option<usize>,
     pub is_cjk: bool,
+    pub is_regex: bool,
     pub item_list_len: usize,
-    pub items: Vec<Item>,
+    pub item_styles: Vec<Styles>,
+    pub item_texts: Vec<String>,
     pub marked_rows: Vec<usize>,
+    pub match_positions: Vec<Vec<usize>>,
+    pub num_duplicates: Option<usize>,
     pub query_string: Arc<String>,
     pub status_message: Option<String>,
     pub total_lines: usize,
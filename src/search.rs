@@ -1,52 +1,299 @@
+use std::cmp::Ordering;
+use std::mem;
 use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::Arc;
 
+use crate::index_set::IndexSet;
+use crate::line_storage::LineStorage;
 use crate::query::Query;
 
 pub struct Request {
-    pub query: Query,
+    pub candidates: Option<Candidates>,
+    pub generation: usize,
+    pub query: Arc<Query>,
     pub start: usize,
 }
 
+/// Lines that can match a query because they matched a broader one. Lines
+/// before `end` that are not listed are known not to match.
+pub struct Candidates {
+    pub line_indices: Arc<Vec<usize>>,
+    pub end: usize,
+}
+
+/// Counts query changes so that the searcher can tell whether a request is
+/// still wanted. Clones share the same counter.
+#[derive(Clone, Default)]
+pub struct Generation(Arc<AtomicUsize>);
+
+impl Generation {
+    pub fn current(&self) -> usize {
+        self.0.load(AtomicOrdering::Relaxed)
+    }
+
+    pub fn advance(&self) {
+        self.0.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+}
+
 pub struct Response {
-    pub query: Query,
+    // The generation of the request.
+    pub generation: usize,
+    pub query: Arc<Query>,
     pub match_info: MatchInfo,
 }
 
 impl Response {
-    pub fn new(query: Query, line_indices: Vec<usize>, index_range: Range<usize>) -> Self {
+    pub fn new(
+        generation: usize,
+        query: Arc<Query>,
+        line_indices: Vec<usize>,
+        points: Vec<i32>,
+        index_range: Range<usize>,
+    ) -> Self {
         Response {
+            generation,
             query,
             match_info: MatchInfo {
-                line_indices,
+                line_indices: line_indices.into(),
+                points,
                 index_range,
             },
         }
     }
 }
 
+/// Relevance of a matched line. Better matches compare as smaller so that
+/// sorting in ascending order puts them first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Score {
+    pub points: i32,
+    pub line_len: usize,
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .points
+            .cmp(&self.points)
+            .then(self.line_len.cmp(&other.line_len))
+    }
+}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 #[derive(Debug)]
 pub struct MatchInfo {
-    pub line_indices: Vec<usize>,
+    pub line_indices: IndexSet,
+    // The points each line scored, or none if matches are not sorted.
+    pub points: Vec<i32>,
     pub index_range: Range<usize>,
 }
 
 impl MatchInfo {
     pub fn merge(&mut self, other: Self) {
-        assert!(self.index_range.start <= other.index_range.end);
-        let overlap_len = self.index_range.end - other.index_range.start;
-        self.line_indices
-            .extend(other.line_indices.into_iter().skip(overlap_len));
+        assert!(other.index_range.start <= self.index_range.end);
+        let end = self.index_range.end;
+        let mut points = other.points.into_iter();
+        for i in other.line_indices.iter() {
+            let line_points = points.next();
+            if i >= end {
+                self.line_indices.push(i);
+                self.points.extend(line_points);
+            }
+        }
         if self.index_range.end < other.index_range.end {
             self.index_range.end = other.index_range.end;
         }
     }
+
+    /// Shrinks the match info to save memory while it is cached.
+    pub fn compact(&mut self) {
+        self.line_indices.compact();
+        self.points.shrink_to_fit();
+    }
+
+    /// Returns the approximate number of bytes allocated for the match info.
+    pub fn heap_size(&self) -> usize {
+        self.line_indices.heap_size() + self.points.capacity() * mem::size_of::<i32>()
+    }
+
+    // Returns the matches at or after line `start` with their scores, sorted
+    // best first. Ties go to the shorter line in `line_storage`.
+    fn ranked_from(&self, start: usize, line_storage: &LineStorage) -> Vec<(Score, usize)> {
+        let mut ranked: Vec<(Score, usize)> = self
+            .points
+            .iter()
+            .zip(self.line_indices.iter())
+            .filter(|&(_, i)| i >= start)
+            .map(|(&points, i)| {
+                let line_len = line_storage.line_len(i);
+                (Score { points, line_len }, i)
+            })
+            .collect();
+        ranked.sort_unstable();
+        ranked
+    }
 }
 
 impl Default for MatchInfo {
     fn default() -> Self {
         MatchInfo {
-            line_indices: Vec::new(),
+            line_indices: IndexSet::new(),
+            points: Vec::new(),
             index_range: 0..0,
         }
     }
 }
+
+/// The matches of a query sorted by relevance. As more lines are searched,
+/// only the new matches are sorted and merged into it.
+pub struct Ranking {
+    // Lines before it are ranked.
+    end: usize,
+    entries: Vec<(Score, usize)>,
+    query_string: String,
+}
+
+impl Ranking {
+    pub fn new(query_string: String, match_info: &MatchInfo, line_storage: &LineStorage) -> Self {
+        Ranking {
+            end: match_info.index_range.end,
+            entries: match_info.ranked_from(0, line_storage),
+            query_string,
+        }
+    }
+
+    /// Returns true if the ranking is up to date with `match_info` of
+    /// `query_string`.
+    pub fn ranks(&self, query_string: &str, match_info: &MatchInfo) -> bool {
+        self.query_string == query_string && self.end == match_info.index_range.end
+    }
+
+    /// Merges the matches of `match_info` that come after the ranked lines,
+    /// e.g. those of a search response for `query_string`. Does nothing if
+    /// it is for another query or lines before it are not ranked yet.
+    pub fn merge(
+        &mut self,
+        query_string: &str,
+        match_info: &MatchInfo,
+        line_storage: &LineStorage,
+    ) {
+        if self.query_string != query_string || match_info.index_range.start > self.end {
+            return;
+        }
+        let new_entries = match_info.ranked_from(self.end, line_storage);
+        if !new_entries.is_empty() {
+            let old_entries = mem::take(&mut self.entries);
+            self.entries = Vec::with_capacity(old_entries.len() + new_entries.len());
+            let mut old_entries = old_entries.into_iter().peekable();
+            let mut new_entries = new_entries.into_iter().peekable();
+            while let (Some(old), Some(new)) = (old_entries.peek(), new_entries.peek()) {
+                if old <= new {
+                    self.entries.push(old_entries.next().unwrap());
+                } else {
+                    self.entries.push(new_entries.next().unwrap());
+                }
+            }
+            self.entries.extend(old_entries);
+            self.entries.extend(new_entries);
+        }
+        if self.end < match_info.index_range.end {
+            self.end = match_info.index_range.end;
+        }
+    }
+
+    pub fn line_indices(&self) -> impl Iterator<Item = usize> + '_ {
+        self.entries.iter().map(|&(_, i)| i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn line_storage(lines: &[&str]) -> LineStorage {
+        let mut line_storage = LineStorage::new(&Config::with_test_args(&[]));
+        line_storage.add_source(0, None, None);
+        for line in lines {
+            line_storage.push(line.as_bytes());
+        }
+        line_storage
+    }
+
+    fn match_info(matches: &[(usize, i32)], index_range: Range<usize>) -> MatchInfo {
+        MatchInfo {
+            line_indices: matches.iter().map(|&(i, _)| i).collect::<Vec<_>>().into(),
+            points: matches.iter().map(|&(_, points)| points).collect(),
+            index_range,
+        }
+    }
+
+    #[test]
+    fn merge_keeps_points_of_new_lines() {
+        let mut info = match_info(&[(1, 10), (3, 30)], 0..4);
+        info.merge(match_info(&[(3, 30), (5, 50)], 2..6));
+        assert_eq!(info.line_indices.iter().collect::<Vec<_>>(), vec![1, 3, 5]);
+        assert_eq!(info.points, vec![10, 30, 50]);
+        assert_eq!(info.index_range, 0..6);
+    }
+
+    #[test]
+    #[should_panic]
+    fn merge_after_a_gap_panics() {
+        let mut info = match_info(&[], 0..0);
+        info.merge(match_info(&[(12, 10)], 10..20));
+    }
+
+    #[test]
+    fn merge_without_points_stays_without_points() {
+        let mut info = match_info(&[], 0..0);
+        info.merge(MatchInfo {
+            line_indices: vec![1, 2].into(),
+            points: Vec::new(),
+            index_range: 0..3,
+        });
+        assert!(info.points.is_empty());
+        assert_eq!(info.heap_size(), info.line_indices.heap_size());
+    }
+
+    #[test]
+    fn ranking_puts_higher_points_then_shorter_lines_first() {
+        let line_storage = line_storage(&["aaaa", "a", "aa", "aaa"]);
+        let info = match_info(&[(0, 5), (1, 1), (2, 5), (3, 9)], 0..4);
+        let ranking = Ranking::new("a".to_owned(), &info, &line_storage);
+        assert_eq!(ranking.line_indices().collect::<Vec<_>>(), vec![3, 2, 0, 1]);
+        assert!(ranking.ranks("a", &info));
+        assert!(!ranking.ranks("b", &info));
+    }
+
+    #[test]
+    fn merged_ranking_equals_full_ranking() {
+        let lines: Vec<String> = (0..40).map(|i| "x".repeat(i % 7 + 1)).collect();
+        let lines: Vec<&str> = lines.iter().map(|s| s.as_ref()).collect();
+        let line_storage = line_storage(&lines);
+        let matches: Vec<(usize, i32)> = (0..40)
+            .filter(|i| i % 3 != 0)
+            .map(|i| (i, (i * 7 % 5) as i32))
+            .collect();
+        let first: Vec<_> = matches.iter().cloned().filter(|&(i, _)| i < 20).collect();
+        let rest: Vec<_> = matches.iter().cloned().filter(|&(i, _)| i >= 20).collect();
+        let mut ranking = Ranking::new("x".to_owned(), &match_info(&first, 0..20), &line_storage);
+        ranking.merge("x", &match_info(&rest, 20..40), &line_storage);
+        // Responses for other queries and ones beyond a gap are ignored.
+        ranking.merge("y", &match_info(&[(41, 9)], 40..42), &line_storage);
+        ranking.merge("x", &match_info(&[(45, 9)], 44..46), &line_storage);
+        let full = Ranking::new("x".to_owned(), &match_info(&matches, 0..40), &line_storage);
+        assert_eq!(
+            ranking.line_indices().collect::<Vec<_>>(),
+            full.line_indices().collect::<Vec<_>>()
+        );
+        assert!(ranking.ranks("x", &match_info(&matches, 0..40)));
+    }
+}
@@ -0,0 +1,112 @@
+use crate::field::Fields;
+use crate::normalizer::Normalizer;
+use crate::query::Query;
+
+/// Builds the text queries are matched against from lines, which is the
+/// selected fields of them, normalized. The buffers are reused across lines
+/// so that searching does not allocate for each of them.
+pub struct SearchText {
+    fields: Option<Fields>,
+    normalizer: Normalizer,
+    origins: Vec<usize>,
+    text: String,
+}
+
+impl SearchText {
+    pub fn new(normalizer: Normalizer, fields: Option<Fields>) -> Self {
+        SearchText {
+            fields,
+            normalizer,
+            origins: Vec::new(),
+            text: String::new(),
+        }
+    }
+
+    /// Returns the search text of the line `s`.
+    pub fn get<'a>(&'a mut self, s: &'a str) -> &'a str {
+        if self.build(s) {
+            &self.text
+        } else {
+            s
+        }
+    }
+
+    /// Returns the sorted byte offsets of the chars in the line `s` that
+    /// `query` matched in its search text.
+    pub fn positions(&mut self, s: &str, query: &Query) -> Vec<usize> {
+        if !self.build(s) {
+            return query.positions(s);
+        }
+        let mut positions: Vec<usize> = query
+            .positions(&self.text)
+            .into_iter()
+            .map(|i| self.origins[i])
+            .collect();
+        positions.sort_unstable();
+        positions.dedup();
+        positions
+    }
+
+    // Builds the search text of `s` in `self.text` and returns true, or
+    // returns false if it would be `s` itself.
+    fn build(&mut self, s: &str) -> bool {
+        self.text.clear();
+        self.origins.clear();
+        match self.fields {
+            Some(ref fields) => {
+                for range in fields.select(s) {
+                    let start = range.start;
+                    self.normalizer.normalize_into(
+                        &s[range],
+                        start,
+                        &mut self.text,
+                        &mut self.origins,
+                    );
+                }
+            }
+            None if self.normalizer.is_identity() => return false,
+            None => {
+                self.normalizer
+                    .normalize_into(s, 0, &mut self.text, &mut self.origins);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::query::QueryEditor;
+
+    fn positions(args: &[&str], query: &str, s: &str) -> Vec<usize> {
+        let config = Config::with_test_args(args);
+        let mut editor = QueryEditor::new(&config);
+        for ch in query.chars() {
+            editor.put_key(crate::key::Key::Char(ch));
+        }
+        let mut search_text = SearchText::new(Normalizer::new(&config), config.search_fields());
+        search_text.positions(s, &editor.query().unwrap())
+    }
+
+    #[test]
+    fn folded_kana_positions_are_in_original_line() {
+        // `ｶ` at 2, `ﾞ` at 5 and `ﾅ` at 8.
+        assert_eq!(positions(&["--fold-kana"], "がな", "x ｶﾞﾅ"), vec![2, 8]);
+        assert_eq!(positions(&["--fold-kana"], "'b", "ａｂ"), vec![3]);
+    }
+
+    #[test]
+    fn stripped_diacritic_positions_are_in_original_line() {
+        assert_eq!(
+            positions(&["--normalize"], "'cafe", "a café"),
+            vec![2, 3, 4, 5]
+        );
+        assert_eq!(
+            positions(&["--normalize"], "'fe", "cafe\u{301}!"),
+            vec![2, 3]
+        );
+        assert_eq!(positions(&["--normalize"], "ee", "éxé"), vec![0, 3]);
+    }
+}
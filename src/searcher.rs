@@ -1,9 +1,21 @@
 use std::cmp;
-use std::sync::mpsc::{Receiver, Sender};
+use std::ops::Range;
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, RwLock};
+use std::thread;
 
+use crate::config::Config;
+use crate::field::Fields;
 use crate::line_storage::LineStorage;
-use crate::search::{Request, Response};
+use crate::normalizer::Normalizer;
+use crate::query::Query;
+use crate::search::{Candidates, Generation, Request, Response};
+use crate::search_text::SearchText;
+use crate::thread_util::WorkerPool;
+
+const TESTS_PER_WORKER: usize = 500_000;
+const MIN_CHUNK_LEN: usize = 10_000;
+const TESTS_PER_GENERATION_CHECK: usize = 1024;
 
 pub enum Input {
     Search(Request),
@@ -14,42 +26,262 @@ pub enum Reply {
 }
 
 pub struct Searcher {
+    fields: Option<Fields>,
+    generation: Generation,
     line_storage: Arc<RwLock<LineStorage>>,
+    normalizer: Normalizer,
+    sorts: bool,
+    worker_pool: WorkerPool,
 }
 
 impl Searcher {
-    pub fn new(line_storage: Arc<RwLock<LineStorage>>) -> Self {
-        Searcher { line_storage }
+    pub fn new(
+        config: &Config,
+        generation: Generation,
+        line_storage: Arc<RwLock<LineStorage>>,
+    ) -> Self {
+        let num_workers = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        Searcher {
+            fields: config.search_fields(),
+            generation,
+            line_storage,
+            normalizer: Normalizer::new(config),
+            sorts: config.sorts(),
+            worker_pool: WorkerPool::new("searcher::worker", num_workers),
+        }
     }
 
     pub fn start(self, input_rx: Receiver<Input>, reply_tx: Sender<Reply>) {
         loop {
             use self::Input::*;
             let reply = match input_rx.recv() {
+                // Requests queued while the user kept typing are skipped.
+                Ok(Search(ref request)) if request.generation != self.generation.current() => {
+                    continue;
+                }
                 Ok(Search(request)) => self.search(request),
                 Err(_) => return,
             };
-            let _dont_care = reply_tx.send(reply).is_ok();
+            if let Some(reply) = reply {
+                let _dont_care = reply_tx.send(reply).is_ok();
+            }
         }
     }
 
-    fn search(&self, request: Request) -> Reply {
-        let Request { query, start } = request;
-        let tests_per_req = 500_000;
+    // Splits the lines to test into chunks, one per worker, and concatenates
+    // their results so that line indices stay in order. Only candidates are
+    // tested if there are any. Returns `None` if the query changed before the
+    // search finished.
+    fn search(&self, request: Request) -> Option<Reply> {
+        let Request {
+            candidates,
+            generation,
+            query,
+            start,
+        } = request;
+        let num_workers = self.worker_pool.size();
+        let max_tests = TESTS_PER_WORKER * num_workers;
+        let len = self.line_storage.read().unwrap().len();
+        let (targets, end) = search_range(start, candidates.as_ref(), len, max_tests);
+        let candidate_line_indices = candidates.map(|candidates| candidates.line_indices);
+        let chunk_len = cmp::max(targets.len().div_ceil(num_workers), MIN_CHUNK_LEN);
+
+        let (result_tx, result_rx) = channel();
+        let mut num_chunks = 0;
+        for chunk_start in targets.clone().step_by(chunk_len) {
+            let range = chunk_start..cmp::min(chunk_start + chunk_len, targets.end);
+            let candidate_line_indices = candidate_line_indices.clone();
+            let line_storage = self.line_storage.clone();
+            let query = query.clone();
+            let result_tx = result_tx.clone();
+            let mut search_text = SearchText::new(self.normalizer, self.fields.clone());
+            let current_generation = self.generation.clone();
+            let sorts = self.sorts;
+            self.worker_pool.execute(move || {
+                // Checked while the lines are locked as they may have been
+                // removed since the request was made.
+                let line_storage = line_storage.read().unwrap();
+                if current_generation.current() != generation {
+                    return;
+                }
+                let is_cancelled = || current_generation.current() != generation;
+                let result = match candidate_line_indices {
+                    Some(ref line_indices) => search_lines(
+                        &line_storage,
+                        &mut search_text,
+                        &query,
+                        line_indices[range].iter().cloned(),
+                        sorts,
+                        is_cancelled,
+                    ),
+                    None => search_lines(
+                        &line_storage,
+                        &mut search_text,
+                        &query,
+                        range,
+                        sorts,
+                        is_cancelled,
+                    ),
+                };
+                if let Some(result) = result {
+                    let _dont_care = result_tx.send((num_chunks, result)).is_ok();
+                }
+            });
+            num_chunks += 1;
+        }
+        drop(result_tx);
+
+        let mut results: Vec<_> = result_rx.iter().collect();
+        if results.len() != num_chunks {
+            return None;
+        }
+        results.sort_by_key(|&(i, _)| i);
         let mut line_indices = Vec::new();
-        let line_storage = self.line_storage.read().unwrap();
-        for (i, line) in line_storage
-            .iter()
-            .enumerate()
-            .skip(start)
-            .take(tests_per_req)
-        {
-            if query.test(line.as_chars()) {
+        let mut points = Vec::new();
+        for (_, (chunk_line_indices, chunk_points)) in results {
+            line_indices.extend(chunk_line_indices);
+            points.extend(chunk_points);
+        }
+        let response = Response::new(generation, query, line_indices, points, start..end);
+        Some(Reply::DidSearch(response))
+    }
+}
+
+// Returns the lines to test in a search from line `start` of `len` lines, up
+// to `max_tests` of them, and the end of the lines the search covers. The
+// lines to test index into `candidates` if any, or else the line storage.
+// Every line in `start..end` that is not tested is known not to match.
+fn search_range(
+    start: usize,
+    candidates: Option<&Candidates>,
+    len: usize,
+    max_tests: usize,
+) -> (Range<usize>, usize) {
+    match candidates {
+        Some(candidates) => {
+            let num_tests = cmp::min(candidates.line_indices.len(), max_tests);
+            // The first candidate left untested may match, so the search
+            // ends before it.
+            let end = candidates
+                .line_indices
+                .get(num_tests)
+                .cloned()
+                .unwrap_or(candidates.end);
+            (0..num_tests, end)
+        }
+        None => {
+            let end = cmp::min(start + max_tests, len);
+            (start..end, end)
+        }
+    }
+}
+
+fn search_lines<I, F>(
+    line_storage: &LineStorage,
+    search_text: &mut SearchText,
+    query: &Query,
+    target_line_indices: I,
+    sorts: bool,
+    is_cancelled: F,
+) -> Option<(Vec<usize>, Vec<i32>)>
+where
+    I: Iterator<Item = usize>,
+    F: Fn() -> bool,
+{
+    let mut line_indices = Vec::new();
+    let mut points = Vec::new();
+    for (n, i) in target_line_indices.enumerate() {
+        if n % TESTS_PER_GENERATION_CHECK == 0 && is_cancelled() {
+            return None;
+        }
+        if line_storage.is_removed(i) {
+            continue;
+        }
+        let line = line_storage.get_unchecked(i);
+        let s = line.to_str();
+        let haystack = search_text.get(&s);
+        if !sorts {
+            if query.test(haystack) {
                 line_indices.push(i);
             }
+        } else if let Some(line_points) = query.score(haystack) {
+            line_indices.push(i);
+            points.push(line_points);
+        }
+    }
+    Some((line_indices, points))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::Key;
+    use crate::query::QueryEditor;
+
+    fn candidates(line_indices: Vec<usize>, end: usize) -> Candidates {
+        Candidates {
+            line_indices: Arc::new(line_indices),
+            end,
+        }
+    }
+
+    #[test]
+    fn lines_are_searched_up_to_max_tests() {
+        assert_eq!(search_range(0, None, 10, 4), (0..4, 4));
+        assert_eq!(search_range(8, None, 10, 4), (8..10, 10));
+        assert_eq!(search_range(10, None, 10, 4), (10..10, 10));
+    }
+
+    #[test]
+    fn candidates_are_searched_up_to_the_first_untested_one() {
+        let all = candidates(vec![3, 5, 9, 12], 20);
+        assert_eq!(search_range(2, Some(&all), 30, 10), (0..4, 20));
+        // Lines 13 to 19 are not listed and so cannot match, but 12 is not
+        // tested yet.
+        assert_eq!(search_range(2, Some(&all), 30, 3), (0..3, 12));
+        assert_eq!(
+            search_range(2, Some(&candidates(vec![], 20)), 30, 3),
+            (0..0, 20)
+        );
+    }
+
+    #[test]
+    fn search_is_cancelled_when_generation_changes() {
+        let config = Config::with_test_args(&[]);
+        let mut line_storage = LineStorage::new(&config);
+        line_storage.add_source(0, None, None);
+        for line in ["foo", "bar", "food"].iter() {
+            line_storage.push(line.as_bytes());
+        }
+        let mut editor = QueryEditor::new(&config);
+        for ch in "fo".chars() {
+            editor.put_key(Key::Char(ch));
         }
-        let end = cmp::min(start + tests_per_req, line_storage.len());
-        let response = Response::new(query, line_indices, start..end);
-        Reply::DidSearch(response)
+        let query = editor.query().unwrap();
+        let mut search_text = SearchText::new(Normalizer::new(&config), None);
+        let generation = Generation::default();
+        let requested = generation.current();
+        let is_cancelled = || generation.current() != requested;
+        let result = search_lines(
+            &line_storage,
+            &mut search_text,
+            &query,
+            0..3,
+            false,
+            is_cancelled,
+        );
+        assert_eq!(result, Some((vec![0, 2], Vec::new())));
+        generation.advance();
+        let result = search_lines(
+            &line_storage,
+            &mut search_text,
+            &query,
+            0..3,
+            false,
+            is_cancelled,
+        );
+        assert_eq!(result, None);
     }
 }
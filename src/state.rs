@@ -2,23 +2,46 @@ use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{Arc, RwLock};
 
 use crate::config::Config;
+use crate::encoding;
+use crate::field::Fields;
 use crate::item_list::ItemList;
 use crate::key::Key;
-use crate::line::Line;
+use crate::line;
 use crate::line_storage::LineStorage;
 use crate::match_info_cache::MatchInfoCache;
+use crate::normalizer::Normalizer;
 use crate::query::QueryEditor;
+use crate::reader::SourceCommand;
 use crate::screen::Screen;
 use crate::screen_data::ScreenData;
-use crate::search::{MatchInfo, Request, Response};
+use crate::search::{Candidates, Generation, Ranking, Request, Response};
+use crate::search_text::SearchText;
 
 pub struct State {
+    display_fields: Option<Fields>,
+    generation: Generation,
+    // Lines of the `--header` text.
+    header: Vec<String>,
     is_cjk: bool,
     item_list: ItemList,
     match_info_cache: MatchInfoCache,
     line_storage: Arc<RwLock<LineStorage>>,
+    normalizer: Normalizer,
+    origin_names: Vec<String>,
+    output_delimiter: u8,
+    prints_origin: bool,
     query_editor: QueryEditor,
+    // The matches of the query shown, sorted if `sorts`.
+    ranking: Option<Ranking>,
+    // Responses to requests older than this are for removed lines.
+    reloaded_generation: usize,
     screen: Screen,
+    search_fields: Option<Fields>,
+    shows_cache_stats: bool,
+    shows_duplicates: bool,
+    shows_origin: bool,
+    sorts: bool,
+    source_command: Option<Arc<SourceCommand>>,
     status_message: Option<String>, // would be used to print debug messages
 }
 
@@ -31,19 +54,43 @@ pub enum Input {
 }
 
 pub enum Reply {
-    Complete(Vec<Arc<Line>>),
+    Complete(Vec<Vec<u8>>),
     SendSearchRequest(Request),
 }
 
 impl State {
-    pub fn new(config: &Config, line_storage: Arc<RwLock<LineStorage>>, screen: Screen) -> Self {
+    pub fn new(
+        config: &Config,
+        generation: Generation,
+        line_storage: Arc<RwLock<LineStorage>>,
+        source_command: Option<Arc<SourceCommand>>,
+        screen: Screen,
+    ) -> Self {
         State {
+            display_fields: config.display_fields(),
+            generation,
+            header: config
+                .header()
+                .map(|s| s.lines().map(|line| line.to_owned()).collect())
+                .unwrap_or_default(),
             is_cjk: config.is_cjk(),
             item_list: ItemList::new(screen.list_view_height()),
-            match_info_cache: MatchInfoCache::new(),
+            match_info_cache: MatchInfoCache::new(config.cache_size()),
             line_storage,
-            query_editor: QueryEditor::new(config.initial_query().unwrap_or(""), config.is_cjk()),
+            normalizer: Normalizer::new(config),
+            origin_names: config.origin_names(),
+            output_delimiter: config.output_delimiter(),
+            prints_origin: config.prints_origin(),
+            query_editor: QueryEditor::new(config),
+            ranking: None,
+            reloaded_generation: 0,
             screen,
+            search_fields: config.search_fields(),
+            shows_cache_stats: config.shows_cache_stats(),
+            shows_duplicates: config.unique().is_some(),
+            shows_origin: config.shows_origin(),
+            sorts: config.sorts(),
+            source_command,
             status_message: None,
         }
     }
@@ -71,14 +118,34 @@ impl State {
                 self.item_list.move_highlight_forward();
                 self.screen.update(self.get_screen_data());
             }
+            PutKey(Key::CtrlL) if self.source_command.is_some() => {
+                self.reload_source();
+                return self.refresh_item_list();
+            }
             PutKey(Key::CtrlM) => {
                 let indices = self.item_list.selected_line_indices();
-                let items = self
-                    .line_storage
-                    .read()
-                    .unwrap()
-                    .get_many_unchecked(indices);
-                return Some(Complete(items));
+                let line_storage = self.line_storage.read().unwrap();
+                let lines = line_storage
+                    .get_many_unchecked(indices)
+                    .iter()
+                    .map(|line| {
+                        // Text around UTF-16 lines is encoded like them so
+                        // that the output stays UTF-16.
+                        let encode = |s: &str| match line.encoding() {
+                            Some(e) if encoding::is_utf16(e) => encoding::encode_utf16(s, e),
+                            _ => s.as_bytes().to_owned(),
+                        };
+                        let mut bytes = Vec::new();
+                        if self.prints_origin {
+                            let prefix = format!("{}:", self.origin_names[line.origin()]);
+                            bytes.extend(encode(&prefix));
+                        }
+                        bytes.extend_from_slice(line.as_bytes());
+                        bytes.extend(encode(&char::from(self.output_delimiter).to_string()));
+                        bytes
+                    })
+                    .collect();
+                return Some(Complete(lines));
             }
             PutKey(Key::CtrlN) => {
                 self.item_list.move_highlight_forward();
@@ -88,62 +155,64 @@ impl State {
                 self.item_list.move_highlight_backward();
                 self.screen.update(self.get_screen_data());
             }
+            PutKey(Key::CtrlR) => {
+                self.query_editor.toggle_regex();
+                self.generation.advance();
+                self.match_info_cache.clear();
+                self.ranking = None;
+                return self.refresh_item_list();
+            }
             PutKey(key) => {
+                let old_query_string = self.query_editor.as_ref().to_owned();
                 self.query_editor.put_key(key);
-                let query_str = self.query_editor.as_ref();
-                if !query_str.is_empty() {
-                    if let Some(&MatchInfo {
-                        line_indices: ref indices,
-                        ref index_range,
-                    }) = self.match_info_cache.get(query_str)
-                    {
-                        let end = index_range.end;
-                        self.item_list.set_line_indices(indices.clone());
-                        self.screen.update(self.get_screen_data());
-                        if end != self.line_storage.read().unwrap().len() {
-                            let request = Request {
-                                query: self.query_editor.query(),
-                                start: end,
-                            };
-                            return Some(SendSearchRequest(request));
-                        }
-                    } else {
-                        let request = Request {
-                            query: self.query_editor.query(),
-                            start: 0,
-                        };
-                        return Some(SendSearchRequest(request));
-                    }
-                } else {
-                    self.item_list
-                        .set_line_index_range(0..self.line_storage.read().unwrap().len());
-                    self.screen.update(self.get_screen_data());
+                if self.query_editor.as_ref() != old_query_string {
+                    self.generation.advance();
                 }
+                return self.refresh_item_list();
             }
             PutSearchResponse(response) => {
-                let Response { query, match_info } = response;
-                let query_string = query.as_ref().to_owned();
-                self.match_info_cache
+                let Response {
+                    generation,
+                    query,
+                    match_info,
+                } = response;
+                if generation < self.reloaded_generation {
+                    return None;
+                }
+                if query.is_regex() != self.query_editor.is_regex() {
+                    return None;
+                }
+                let query_string = (*query).as_ref().to_owned();
+                if let Some(ref mut ranking) = self.ranking {
+                    let line_storage = self.line_storage.read().unwrap();
+                    ranking.merge(&query_string, &match_info, &line_storage);
+                }
+                let is_cached = self
+                    .match_info_cache
                     .insert(query_string.clone(), match_info);
-                let &MatchInfo {
-                    ref line_indices,
-                    ref index_range,
-                } = self.match_info_cache.get(&query_string).unwrap();
-                let end = index_range.end;
-                self.item_list.set_line_indices(line_indices.clone());
-                self.screen.update(self.get_screen_data());
-                if query_string == self.query_editor.as_ref()
-                    && end < self.line_storage.read().unwrap().len()
-                {
+                if query_string != self.query_editor.as_ref() {
+                    return None;
+                }
+                // The lines the evicted entry covered are searched again.
+                let end = if is_cached {
+                    let end = self.show_matches(&query_string);
+                    self.screen.update(self.get_screen_data());
+                    end
+                } else {
+                    0
+                };
+                if end < self.line_storage.read().unwrap().len() {
                     let request = Request {
-                        query: self.query_editor.query(),
+                        candidates: self.candidates(end),
+                        generation: self.generation.current(),
+                        query,
                         start: end,
                     };
                     return Some(SendSearchRequest(request));
                 }
             }
             ReaderDidFinish => {
-                if self.line_storage.read().unwrap().len() == 0 {
+                if self.line_storage.read().unwrap().is_empty() {
                     return Some(Complete(Vec::new()));
                 }
             }
@@ -152,57 +221,201 @@ impl State {
                 self.screen.update(self.get_screen_data());
             }
             UpdateScreen => {
-                let query_str = self.query_editor.as_ref();
-                if !query_str.is_empty() {
-                    if let Some(&MatchInfo {
-                        line_indices: ref indices,
-                        ref index_range,
-                    }) = self.match_info_cache.get(query_str)
-                    {
-                        let end = index_range.end;
-                        self.item_list.set_line_indices(indices.clone());
-                        self.screen.update(self.get_screen_data());
-                        if end != self.line_storage.read().unwrap().len() {
-                            let request = Request {
-                                query: self.query_editor.query(),
-                                start: end,
-                            };
-                            return Some(SendSearchRequest(request));
-                        }
-                    } else {
-                        let request = Request {
-                            query: self.query_editor.query(),
-                            start: 0,
-                        };
-                        return Some(SendSearchRequest(request));
-                    }
-                } else {
-                    self.item_list
-                        .set_line_index_range(0..self.line_storage.read().unwrap().len());
+                return self.refresh_item_list();
+            }
+        }
+        None
+    }
+
+    // Reruns the source command, with the current query if it takes one,
+    // replacing all lines with its output.
+    fn reload_source(&mut self) {
+        // Searches are cancelled before the lines they read are removed.
+        self.generation.advance();
+        self.reloaded_generation = self.generation.current();
+        let source_command = self.source_command.as_ref().unwrap();
+        source_command.stop();
+        self.line_storage.write().unwrap().reset();
+        self.match_info_cache.clear();
+        self.ranking = None;
+        self.item_list.set_line_indices(Vec::new());
+        self.item_list.clear_marks();
+        source_command.run(self.query_editor.as_ref());
+    }
+
+    fn refresh_item_list(&mut self) -> Option<Reply> {
+        use self::Reply::*;
+        let query_str = self.query_editor.as_ref();
+        if !query_str.is_empty() {
+            let query = match self.query_editor.query() {
+                Ok(query) => Arc::new(query),
+                Err(message) => {
+                    // Keep showing the last valid result while the user is
+                    // still typing.
+                    self.status_message = Some(message);
                     self.screen.update(self.get_screen_data());
+                    return None;
                 }
+            };
+            self.status_message = None;
+            if self.match_info_cache.get(query_str).is_some() {
+                let query_string = query_str.to_owned();
+                let end = self.show_matches(&query_string);
+                self.screen.update(self.get_screen_data());
+                if end != self.line_storage.read().unwrap().len() {
+                    let request = Request {
+                        candidates: self.candidates(end),
+                        generation: self.generation.current(),
+                        query,
+                        start: end,
+                    };
+                    return Some(SendSearchRequest(request));
+                }
+            } else {
+                let request = Request {
+                    candidates: self.candidates(0),
+                    generation: self.generation.current(),
+                    query,
+                    start: 0,
+                };
+                return Some(SendSearchRequest(request));
+            }
+        } else {
+            self.status_message = None;
+            let line_storage = self.line_storage.read().unwrap();
+            if line_storage.num_removed() == 0 {
+                self.item_list.set_line_index_range(0..line_storage.len());
+            } else {
+                let line_indices = (0..line_storage.len())
+                    .filter(|&i| !line_storage.is_removed(i))
+                    .collect();
+                self.item_list.set_line_indices(line_indices);
             }
+            drop(line_storage);
+            self.screen.update(self.get_screen_data());
         }
         None
     }
 
+    // Lists the cached matches of `query_string`, leaving out the lines
+    // replaced by a duplicate since they were matched, and returns the end of
+    // the lines searched.
+    fn show_matches(&mut self, query_string: &str) -> usize {
+        let match_info = self.match_info_cache.peek(query_string).unwrap();
+        let line_storage = self.line_storage.read().unwrap();
+        let mut line_indices: Vec<usize> = if self.sorts {
+            if !self
+                .ranking
+                .as_ref()
+                .is_some_and(|ranking| ranking.ranks(query_string, match_info))
+            {
+                self.ranking = Some(Ranking::new(
+                    query_string.to_owned(),
+                    match_info,
+                    &line_storage,
+                ));
+            }
+            self.ranking.as_ref().unwrap().line_indices().collect()
+        } else {
+            match_info.line_indices.iter().collect()
+        };
+        if line_storage.num_removed() > 0 {
+            line_indices.retain(|&i| !line_storage.is_removed(i));
+        }
+        drop(line_storage);
+        self.item_list.set_line_indices(line_indices);
+        match_info.index_range.end
+    }
+
+    // Returns the lines from `start` on that matched the longest cached query
+    // the current one narrows, so that the searcher does not have to test
+    // the others.
+    fn candidates(&self, start: usize) -> Option<Candidates> {
+        let query_str = self.query_editor.as_ref();
+        let match_info = query_str
+            .char_indices()
+            .rev()
+            .map(|(i, _)| &query_str[..i])
+            .filter(|prefix| self.query_editor.narrows(prefix))
+            .filter_map(|prefix| self.match_info_cache.peek(prefix))
+            .find(|match_info| match_info.index_range.end > start)?;
+        let line_indices = match_info
+            .line_indices
+            .iter()
+            .filter(|&i| i >= start)
+            .collect();
+        Some(Candidates {
+            line_indices: Arc::new(line_indices),
+            end: match_info.index_range.end,
+        })
+    }
+
     fn get_screen_data(&self) -> ScreenData {
         let indices = self.item_list.line_indices_in_clipping_range();
-        let items = self
-            .line_storage
-            .read()
-            .unwrap()
-            .get_many_unchecked(indices);
+        let line_storage = self.line_storage.read().unwrap();
+        let lines = line_storage.get_many_unchecked(indices);
+        let mut search_text = SearchText::new(self.normalizer, self.search_fields.clone());
+        let query = match self.query_editor.query() {
+            Ok(query) if !self.query_editor.as_ref().is_empty() => Some(query),
+            _ => None,
+        };
+        let mut item_styles = Vec::with_capacity(lines.len());
+        let mut item_texts = Vec::with_capacity(lines.len());
+        let mut match_positions = Vec::with_capacity(lines.len());
+        for line in lines.iter() {
+            let (s, styles) = line.to_styled();
+            let positions = match query {
+                Some(ref query) => search_text.positions(&s, query),
+                None => Vec::new(),
+            };
+            let mut display_line =
+                line::to_display(&s, self.display_fields.as_ref(), positions, styles);
+            if self.shows_origin {
+                let prefix = format!("{}:", self.origin_names[line.origin()]);
+                for i in display_line.positions.iter_mut() {
+                    *i += prefix.len();
+                }
+                for (i, _) in display_line.styles.iter_mut() {
+                    *i += prefix.len();
+                }
+                display_line.text.insert_str(0, &prefix);
+            }
+            item_styles.push(display_line.styles);
+            item_texts.push(display_line.text);
+            match_positions.push(display_line.positions);
+        }
+        let header = self
+            .header
+            .iter()
+            .cloned()
+            .chain(line_storage.header().iter().map(|s| {
+                line::to_display(s, self.display_fields.as_ref(), Vec::new(), Vec::new()).text
+            }))
+            .collect();
         ScreenData {
-            cursor_index: self.query_editor.cursor_position(),
+            cache_stats: if self.shows_cache_stats {
+                Some(self.match_info_cache.stats())
+            } else {
+                None
+            },
+            cursor_index: self.query_editor.cursor_column(),
+            header,
             highlighted_row: self.item_list.highlighted_row(),
             is_cjk: self.is_cjk,
+            is_regex: self.query_editor.is_regex(),
             item_list_len: self.item_list.len(),
-            items,
+            item_styles,
+            item_texts,
             marked_rows: self.item_list.marked_rows(),
+            match_positions,
+            num_duplicates: if self.shows_duplicates {
+                Some(line_storage.num_duplicates())
+            } else {
+                None
+            },
             query_string: Arc::new(self.query_editor.as_ref().to_owned()),
             status_message: self.status_message.clone(),
-            total_lines: self.line_storage.read().unwrap().len(),
+            total_lines: line_storage.len() - line_storage.num_removed(),
         }
     }
 }
@@ -1,3 +1,5 @@
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
 use std::thread;
 
 pub fn spawn_with_name<N, F, T>(name: N, f: F) -> thread::JoinHandle<T>
@@ -9,3 +11,40 @@ where
 {
     thread::Builder::new().name(name.into()).spawn(f).unwrap()
 }
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of threads that run jobs in the order they are submitted.
+pub struct WorkerPool {
+    job_tx: Sender<Job>,
+    size: usize,
+}
+
+impl WorkerPool {
+    pub fn new(name: &str, size: usize) -> Self {
+        let (job_tx, job_rx) = channel::<Job>();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        for i in 0..size {
+            let job_rx = job_rx.clone();
+            spawn_with_name(format!("{}{}", name, i), move || loop {
+                let job = match job_rx.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => return,
+                };
+                job();
+            });
+        }
+        WorkerPool { job_tx, size }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.job_tx.send(Box::new(f)).unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::mem;
+
+/// Which of identical lines `--unique` keeps.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Occurrence {
+    First,
+    Last,
+}
+
+/// What to do with a line put to a `Deduplicator`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Keep,
+    Drop,
+    /// Keep the line and remove the identical one at the index.
+    Replace(usize),
+}
+
+/// Remembers the lines seen so far to tell duplicates. Lines are not copied
+/// but known by their hashes and indices, so the bytes of the kept line at an
+/// index are compared against when the hashes are the same.
+pub struct Deduplicator {
+    // The index of the kept line by the hash of its bytes.
+    line_indices: HashMap<u64, usize>,
+    // The indices of kept lines that have the same hash as the one in
+    // `line_indices` but different bytes, which is rare.
+    colliding_line_indices: HashMap<u64, Vec<usize>>,
+    occurrence: Occurrence,
+}
+
+impl Deduplicator {
+    pub fn new(occurrence: Occurrence) -> Self {
+        Deduplicator {
+            line_indices: HashMap::new(),
+            colliding_line_indices: HashMap::new(),
+            occurrence,
+        }
+    }
+
+    /// Puts `line` at `index`. `is_same(i)` tells if the kept line at `i` has
+    /// the same bytes as `line`.
+    pub fn put<F>(&mut self, line: &[u8], index: usize, is_same: F) -> Verdict
+    where
+        F: Fn(usize) -> bool,
+    {
+        let hash = hash(line);
+        let kept_index = match self.line_indices.entry(hash) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(index);
+                return Verdict::Keep;
+            }
+        };
+        let kept_index = if is_same(*kept_index) {
+            kept_index
+        } else {
+            let colliding = self.colliding_line_indices.entry(hash).or_default();
+            match colliding.iter().position(|&i| is_same(i)) {
+                Some(i) => &mut colliding[i],
+                None => {
+                    colliding.push(index);
+                    return Verdict::Keep;
+                }
+            }
+        };
+        match self.occurrence {
+            Occurrence::First => Verdict::Drop,
+            Occurrence::Last => Verdict::Replace(mem::replace(kept_index, index)),
+        }
+    }
+
+    /// Forgets all lines seen.
+    pub fn clear(&mut self) {
+        self.line_indices.clear();
+        self.colliding_line_indices.clear();
+    }
+}
+
+fn hash(line: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    line.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Puts `lines` one after another as `LineStorage` does, keeping the
+    // dropped ones out of the indices, and returns the verdicts.
+    fn put_all(occurrence: Occurrence, lines: &[&str]) -> Vec<Verdict> {
+        let mut deduplicator = Deduplicator::new(occurrence);
+        let mut kept = Vec::new();
+        lines
+            .iter()
+            .map(|&line| {
+                let verdict = deduplicator.put(line.as_bytes(), kept.len(), |i| kept[i] == line);
+                if verdict != Verdict::Drop {
+                    kept.push(line);
+                }
+                verdict
+            })
+            .collect()
+    }
+
+    #[test]
+    fn first_occurrence_is_kept() {
+        use self::Verdict::*;
+        assert_eq!(
+            put_all(Occurrence::First, &["a", "b", "a", "c", "b", "a"]),
+            vec![Keep, Keep, Drop, Keep, Drop, Drop]
+        );
+    }
+
+    #[test]
+    fn last_occurrence_replaces_the_kept_one() {
+        use self::Verdict::*;
+        assert_eq!(
+            put_all(Occurrence::Last, &["a", "b", "a", "c", "b", "a"]),
+            vec![Keep, Keep, Replace(0), Keep, Replace(1), Replace(2)]
+        );
+    }
+
+    #[test]
+    fn lines_with_the_same_hash_are_compared() {
+        use self::Verdict::*;
+        let mut deduplicator = Deduplicator::new(Occurrence::Last);
+        let lines = ["a", "b", "c", "b", "a"];
+        let mut kept: Vec<&str> = Vec::new();
+        let verdicts: Vec<Verdict> = lines
+            .iter()
+            .map(|&line| {
+                // Every line is put with the same bytes, and so the same
+                // hash, but compared as itself.
+                let verdict = deduplicator.put(b"", kept.len(), |i| kept[i] == line);
+                kept.push(line);
+                verdict
+            })
+            .collect();
+        assert_eq!(verdicts, vec![Keep, Keep, Keep, Replace(1), Replace(0)]);
+        assert_eq!(deduplicator.colliding_line_indices[&hash(b"")], vec![3, 2]);
+    }
+
+    #[test]
+    fn cleared_lines_are_forgotten() {
+        let mut deduplicator = Deduplicator::new(Occurrence::First);
+        assert_eq!(deduplicator.put(b"a", 0, |_| true), Verdict::Keep);
+        deduplicator.clear();
+        assert_eq!(deduplicator.put(b"a", 0, |_| true), Verdict::Keep);
+    }
+}
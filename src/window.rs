@@ -2,6 +2,8 @@ use ncurses as nc;
 use std::cmp;
 use unicode_width::UnicodeWidthChar;
 
+use crate::ansi::{Style, Styles};
+use crate::screen;
 use crate::screen_data::ScreenData;
 
 pub struct Window {
@@ -66,11 +68,22 @@ impl WindowImpl for Gutter {
     }
 }
 
+pub struct Header;
+
+impl WindowImpl for Header {
+    fn draw(&self, win: nc::WINDOW, r: Rect, sd: &ScreenData) {
+        for (y, s) in sd.header.iter().take(r.height as usize).enumerate() {
+            let sliced = slice_by_width(s, r.width as usize, sd.is_cjk);
+            nc::mvwaddstr(win, y as i32, 0, sliced);
+        }
+    }
+}
+
 pub struct MiniBuf;
 
 impl WindowImpl for MiniBuf {
     fn draw(&self, win: nc::WINDOW, _r: Rect, sd: &ScreenData) {
-        nc::mvwaddstr(win, 0, 0, &*sd.query_string);
+        nc::mvwaddstr(win, 0, 0, &sd.query_string);
     }
 }
 
@@ -93,21 +106,81 @@ pub struct ListView;
 
 impl WindowImpl for ListView {
     fn draw(&self, win: nc::WINDOW, r: Rect, sd: &ScreenData) {
-        let num_lines = cmp::min(sd.items.len(), r.height as usize);
-        for (y, item) in sd.items.iter().take(num_lines).enumerate() {
-            let s = item.as_str();
+        let num_lines = cmp::min(sd.item_texts.len(), r.height as usize);
+        for (y, s) in sd.item_texts.iter().take(num_lines).enumerate() {
             let sliced = slice_by_width(s, r.width as usize, sd.is_cjk);
-            nc::mvwaddstr(win, y as i32, 0, sliced);
+            nc::wmove(win, y as i32, 0);
+            draw_highlighted(win, sliced, &sd.match_positions[y], &sd.item_styles[y]);
         }
     }
 }
 
+// Draws `s` in `styles` highlighting the chars at `positions` (byte
+// offsets), both of which must be sorted.
+fn draw_highlighted(win: nc::WINDOW, s: &str, positions: &[usize], styles: &Styles) {
+    let mut positions = positions.iter().peekable();
+    let mut styles = styles.iter().peekable();
+    let mut run_start = 0;
+    let mut run_is_match = false;
+    let mut run_style = Style::default();
+    for (offset, _) in s.char_indices() {
+        let is_match = positions.peek() == Some(&&offset);
+        if is_match {
+            positions.next();
+        }
+        let mut style = run_style;
+        if let Some(&&(_, next_style)) = styles.peek().filter(|&&&(i, _)| i == offset) {
+            styles.next();
+            style = next_style;
+        }
+        if is_match != run_is_match || style != run_style {
+            draw_run(win, &s[run_start..offset], run_is_match, run_style);
+            run_start = offset;
+            run_is_match = is_match;
+            run_style = style;
+        }
+    }
+    draw_run(win, &s[run_start..], run_is_match, run_style);
+}
+
+fn draw_run(win: nc::WINDOW, s: &str, is_match: bool, style: Style) {
+    if s.is_empty() {
+        return;
+    }
+    let attr = screen::style_attr(style, is_match);
+    if attr == 0 {
+        nc::waddstr(win, s);
+    } else {
+        nc::wattron(win, attr);
+        nc::waddstr(win, s);
+        nc::wattroff(win, attr);
+    }
+}
+
 pub struct StatusLine;
 
 impl WindowImpl for StatusLine {
     fn draw(&self, win: nc::WINDOW, _r: Rect, sd: &ScreenData) {
         let msg = sd.status_message.as_ref().map(|s| s.as_ref()).unwrap_or("");
-        let s = format!("{}/{} {}", sd.item_list_len, sd.total_lines, msg);
+        let mode = if sd.is_regex { " [regex]" } else { "" };
+        let stats = match sd.cache_stats {
+            Some(stats) => format!(
+                " [cache: {} hits, {} misses, {} evictions, {} KiB]",
+                stats.hits,
+                stats.misses,
+                stats.evictions,
+                stats.size / 1024
+            ),
+            None => String::new(),
+        };
+        let duplicates = match sd.num_duplicates {
+            Some(n) => format!(" [{} duplicates]", n),
+            None => String::new(),
+        };
+        let s = format!(
+            "{}/{}{}{}{} {}",
+            sd.item_list_len, sd.total_lines, mode, duplicates, stats, msg
+        );
         nc::mvwaddstr(win, 0, 0, &s);
     }
 }